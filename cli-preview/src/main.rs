@@ -66,26 +66,25 @@ fn main() -> Result<(), CLIPreviewError> {
             //     accepted = Accepted::Unknown;
             //     break 'event_loop;
             // }
-            show_image::event::WindowEvent::KeyboardInput(input) => match input {
-                WindowKeyboardInputEvent { input, .. } => match input {
-                    show_image::event::KeyboardInput { key_code, .. } => match key_code {
-                        Some(show_image::event::VirtualKeyCode::Y) => {
-                            accepted = Accepted::Yes;
-                            break 'event_loop;
-                        }
-                        Some(show_image::event::VirtualKeyCode::N) => {
-                            accepted = Accepted::No;
-                            break 'event_loop;
-                        }
-                        Some(VirtualKeyCode::Q) => {
-                            break 'event_loop;
-                        }
-                        Some(VirtualKeyCode::Escape) => {
-                            break 'event_loop;
-                        }
-                        _ => {}
-                    },
-                },
+            show_image::event::WindowEvent::KeyboardInput(WindowKeyboardInputEvent {
+                input: show_image::event::KeyboardInput { key_code, .. },
+                ..
+            }) => match key_code {
+                Some(show_image::event::VirtualKeyCode::Y) => {
+                    accepted = Accepted::Yes;
+                    break 'event_loop;
+                }
+                Some(show_image::event::VirtualKeyCode::N) => {
+                    accepted = Accepted::No;
+                    break 'event_loop;
+                }
+                Some(VirtualKeyCode::Q) => {
+                    break 'event_loop;
+                }
+                Some(VirtualKeyCode::Escape) => {
+                    break 'event_loop;
+                }
+                _ => {}
             },
             _ => {}
         }
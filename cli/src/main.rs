@@ -1,19 +1,19 @@
-// TODO: Figure out how to handle non-precut labels
-// TODO: Figure out how to handle 'fruit' labels
-// TODO: Implement templates with fixed font sizes and positions
-// TODO: Implement 'arbitrary image' feature
+mod probe;
+mod queue;
+mod watch;
 
 use std::{
+    collections::HashMap,
     fs,
-    io::{self, Cursor, Write},
-    path::PathBuf,
-    process::{exit, Command, Stdio},
+    io::{self, Cursor, Read, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Duration,
 };
 
 use advmac::{MacAddr6, ParseError};
-use bluetooth_serial_port_async::{BtAddr, BtError, BtSocket};
-use clap::{Parser, Subcommand};
-use d30::D30Scale;
+use clap::{error::ErrorKind, CommandFactory, Parser, Subcommand};
+use d30::{capture::CaptureWriter, D30Printer, D30Scale};
 use image::{DynamicImage, ImageError, ImageFormat};
 use inquire::InquireError;
 use log::{debug, error, info, trace, warn};
@@ -33,36 +33,543 @@ struct Arguments {
 enum Commands {
     #[clap(short_flag = 't')]
     PrintText(ArgsPrintText),
+    /// Print a TOML or JSON label template, filling in its `{{placeholders}}`
+    PrintTemplate(ArgsPrintTemplate),
+    /// Print an image file, fit to the label. `-` reads the image from stdin
+    PrintImage(ArgsPrintImage),
+    /// Watch a directory, printing `.txt`, `.png` and `.toml` label files dropped into it
+    Watch(ArgsWatch),
+    /// Manage labels that were queued because the printer couldn't be reached
+    Queue(ArgsQueue),
+    /// Resend a file recorded with `--capture`, byte for byte
+    Replay(ArgsReplay),
+    /// List the printer models we know about
+    Models,
+    /// Interactive shell for sending raw commands to a printer and decoding the responses
+    Probe(ArgsProbe),
+    /// Print a crosshair and rulers, and store how far off center they came out for the device
+    Calibrate(ArgsCalibrate),
 }
 
+// Options shared by every command that prints a single label
 #[derive(clap::Args, Debug, Clone)]
-struct ArgsPrintText {
+struct ArgsPrint {
     #[arg(long)]
     dry_run: bool,
     #[arg(short, long)]
     device: Option<String>,
+    /// Printer model. Defaults to the one configured for the device, or the D30
+    #[arg(long)]
+    model: Option<d30::Model>,
+    /// Label size: a profile like `12x40`, a custom profile from the config, or `<width>x<length>` in mm
+    #[arg(long)]
+    media: Option<String>,
+    #[arg(short, long)]
+    preview: bool,
+    #[arg(short, long)]
+    #[arg(default_value = "1")]
+    number_of_images: i32,
+    #[command(flatten)]
+    retry: ArgsRetry,
+    /// If the printer can't be reached, queue the label instead of failing
+    #[arg(short, long)]
+    queue: bool,
+    /// Record all traffic with the printer to this file
+    #[arg(long)]
+    capture: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsPrintText {
     text: String,
+    /// Font size, like `40`, `12pt` or `4mm`, or `auto` to fit the label. Bare numbers are in pixels
     #[arg(short, long)]
     #[arg(default_value = "auto")]
     scale: D30Scale,
+    /// Smallest font size `--scale auto` may pick
     #[arg(long)]
-    #[arg(default_value = "0")]
-    minus_scale: f32,
+    min_scale: Option<d30::Length>,
+    /// Largest font size `--scale auto` may pick
+    #[arg(long)]
+    max_scale: Option<d30::Length>,
+    /// Replaced by `--min-scale` and `--max-scale`, ignored
+    #[arg(long)]
+    #[arg(hide = true)]
+    minus_scale: Option<f32>,
+    /// One to four lengths, like `2mm` or `1mm 2mm`, in the same order as CSS. Bare numbers are in pixels
     #[arg(short, long)]
     #[arg(default_value = "15.0")]
-    margins: f32,
+    margins: d30::Margins,
+    #[command(flatten)]
+    tape: ArgsTape,
+    #[command(flatten)]
+    layout: ArgsTextLayout,
+    #[command(flatten)]
+    flag: ArgsFlag,
+    /// Stack the characters upright, one below the other, along the label
+    #[arg(long, conflicts_with = "flag")]
+    portrait: bool,
+    #[command(flatten)]
+    orientation: ArgsOrientation,
+    #[command(flatten)]
+    print: ArgsPrint,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsPrintTemplate {
+    /// Template file. `.json` files are read as JSON, anything else as TOML
+    template: PathBuf,
+    /// Value for a placeholder, as `name=value`. Can be given multiple times
+    #[arg(long = "set", value_parser = parse_key_value)]
+    values: Vec<(String, String)>,
+    #[command(flatten)]
+    orientation: ArgsOrientation,
+    #[command(flatten)]
+    tape: ArgsTape,
+    #[command(flatten)]
+    print: ArgsPrint,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsPrintImage {
+    /// Any format the `image` crate can read, an SVG, or `-` for stdin
+    image: PathBuf,
+    #[command(flatten)]
+    processing: ArgsImage,
+    #[command(flatten)]
+    orientation: ArgsOrientation,
+    #[command(flatten)]
+    tape: ArgsTape,
+    #[command(flatten)]
+    print: ArgsPrint,
+}
+
+// How images are turned into something the printer can print
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsImage {
+    /// `contain`, `cover` (fill and crop), `stretch`, or `center` at one pixel per dot
+    #[arg(long)]
+    #[arg(default_value = "contain")]
+    fit: d30::FitMode,
+    /// `nearest` for pixel art, `triangle`, `catmull-rom`, `gaussian` or `lanczos` for photos
+    #[arg(long)]
+    #[arg(default_value = "triangle")]
+    resample: d30::Resample,
+    /// Crop white and transparent borders first
+    #[arg(long)]
+    trim: bool,
+    /// Turn the image so its long side runs along the label
+    #[arg(long)]
+    auto_rotate: bool,
+    /// Color that transparent parts of the image are blended onto: `white`, `black` or `#rrggbb`
+    #[arg(long, value_parser = parse_color)]
+    #[arg(default_value = "white")]
+    background: image::Rgb<u8>,
+    /// How colors become gray: `luma`, a single channel (`red`, `green` or `blue`),
+    /// or `non-white` to print everything that isn't white
+    #[arg(long)]
+    #[arg(default_value = "luma")]
+    grayscale: d30::Grayscale,
+    /// From -255 to 255
+    #[arg(long)]
+    #[arg(default_value = "0", allow_negative_numbers = true)]
+    brightness: i32,
+    /// Contrast change in percent
+    #[arg(long)]
+    #[arg(default_value = "0", allow_negative_numbers = true)]
+    contrast: f32,
+    /// Above 1 lightens the midtones, below 1 darkens them
+    #[arg(long)]
+    #[arg(default_value = "1")]
+    gamma: f32,
+    /// Unsharp mask radius in pixels
+    #[arg(long)]
+    #[arg(default_value = "0")]
+    sharpen: f32,
+    /// Print the negative of the image
+    #[arg(long)]
+    invert: bool,
+    /// `edges` to print only the outlines, or `sketch` for a pencil drawing look
+    #[arg(long)]
+    #[arg(default_value = "none")]
+    effect: d30::Effect,
+    /// `threshold`, `floyd-steinberg`, `atkinson`, `stucki`, `bayer4` or `bayer8`
+    #[arg(long)]
+    #[arg(default_value = "floyd-steinberg")]
+    dither: d30::Dither,
+    /// For `--dither threshold`: ink level from 0 to 255 above which pixels are printed,
+    /// `otsu` to pick one automatically, or `adaptive[:<radius>]` to compare pixels to their surroundings
+    #[arg(long)]
+    threshold: Option<d30::Threshold>,
+}
+
+impl ArgsImage {
+    fn fit_options(&self) -> d30::FitOptions {
+        d30::FitOptions {
+            mode: self.fit,
+            resample: self.resample,
+            trim: self.trim,
+            auto_rotate: self.auto_rotate,
+            background: self.background,
+            grayscale: self.grayscale,
+            adjustments: d30::Adjustments {
+                brightness: self.brightness,
+                contrast: self.contrast,
+                gamma: self.gamma,
+                sharpen: self.sharpen,
+                invert: self.invert,
+                effect: self.effect,
+            },
+        }
+    }
+
+    fn fit(&self, image: &DynamicImage, canvas: d30::Canvas) -> DynamicImage {
+        d30::fit_image_with(image, canvas, &self.fit_options())
+    }
+
+    fn binarize(&self, image: &DynamicImage) -> DynamicImage {
+        if self.threshold.is_some() && self.dither != d30::Dither::Threshold {
+            warn!("--threshold only applies to `--dither threshold`. Ignoring it");
+        }
+        d30::binarize(image, self.dither, self.threshold.unwrap_or_default())
+    }
+}
+
+fn parse_color(s: &str) -> Result<image::Rgb<u8>, String> {
+    match s {
+        "white" => Ok(image::Rgb([255, 255, 255])),
+        "black" => Ok(image::Rgb([0, 0, 0])),
+        _ => {
+            let hex = s.strip_prefix('#').unwrap_or(s);
+            match hex::decode(hex).as_deref() {
+                Ok(&[r, g, b]) => Ok(image::Rgb([r, g, b])),
+                _ => Err(format!(
+                    "Invalid color: {}. Expected `white`, `black` or `#rrggbb`",
+                    s
+                )),
+            }
+        }
+    }
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.trim().to_string(), value.to_string()))
+        .ok_or_else(|| format!("Invalid value: {}. Expected `name=value`", s))
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsWatch {
+    /// Directory to watch. Processed files are moved to `done/` or `failed/` inside it
+    dir: PathBuf,
+    #[arg(long)]
+    dry_run: bool,
     #[arg(short, long)]
-    preview: bool,
+    device: Option<String>,
+    /// Printer model. Defaults to the one configured for the device, or the D30
+    #[arg(long)]
+    model: Option<d30::Model>,
+    /// Label size: a profile like `12x40`, a custom profile from the config, or `<width>x<length>` in mm
+    #[arg(long)]
+    media: Option<String>,
+    #[arg(short, long)]
+    #[arg(default_value = "auto")]
+    scale: D30Scale,
+    #[arg(short, long)]
+    #[arg(default_value = "15.0")]
+    margins: d30::Margins,
     #[arg(short, long)]
     #[arg(default_value = "1")]
     number_of_images: i32,
+    #[command(flatten)]
+    tape: ArgsTape,
+    #[command(flatten)]
+    retry: ArgsRetry,
+    /// Record all traffic with the printer to this file
+    #[arg(long)]
+    capture: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsReplay {
+    /// Capture file, as written by `--capture`
+    file: PathBuf,
+    #[arg(short, long)]
+    device: Option<String>,
+    /// Send everything back to back, instead of reproducing the delays between writes
+    #[arg(long)]
+    no_delay: bool,
+    #[command(flatten)]
+    retry: ArgsRetry,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsProbe {
+    #[arg(short, long)]
+    device: Option<String>,
+    /// Printer model. Defaults to the one configured for the device, or the D30
+    #[arg(long)]
+    model: Option<d30::Model>,
+    #[arg(long)]
+    dry_run: bool,
+    /// Session log, in the capture format. Defaults to a new file in the XDG state directory
+    #[arg(short, long)]
+    log: Option<PathBuf>,
+    #[command(flatten)]
+    retry: ArgsRetry,
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsCalibrate {
+    #[arg(short, long)]
+    device: Option<String>,
+    /// Printer model. Defaults to the one configured for the device, or the D30
+    #[arg(long)]
+    model: Option<d30::Model>,
+    /// Label size: a profile like `12x40`, a custom profile from the config, or `<width>x<length>` in mm
+    #[arg(long)]
+    media: Option<String>,
+    /// Ask for the measurements, but don't save them
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    retry: ArgsRetry,
+}
+
+// Options for continuous tape. They don't apply to die-cut labels
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsTape {
+    /// Label length, like `40` or `40mm`. Bare numbers are in mm. By default, labels on continuous
+    /// tape are as long as their content
+    #[arg(long)]
+    length: Option<d30::Length>,
+    /// Blank tape between labels. Bare numbers are in mm
+    #[arg(long)]
+    #[arg(default_value = "0")]
+    spacing: d30::Length,
+    /// Print a dashed line to cut along between labels
+    #[arg(long)]
+    cut_marks: bool,
+}
+
+impl ArgsTape {
+    fn canvas(&self, model: d30::Model, media: &d30::Media) -> d30::Canvas {
+        let spec = model.spec();
+        let canvas = spec.canvas(media);
+        match self.length {
+            Some(length) if canvas.auto_length => {
+                let mut canvas = canvas.resized(dots(length, spec.dpi));
+                canvas.auto_length = false;
+                canvas
+            }
+            Some(_) => {
+                warn!("--length only applies to continuous tape. Ignoring it");
+                canvas
+            }
+            None => canvas,
+        }
+    }
+
+    /// Add the spacing and cut marks to a rendered label
+    fn separate(&self, model: d30::Model, media: &d30::Media, image: DynamicImage) -> DynamicImage {
+        if self.spacing.value <= 0.0 && !self.cut_marks {
+            return image;
+        }
+        if media.kind() != d30::MediaKind::Continuous {
+            warn!("Spacing and cut marks only apply to continuous tape. Ignoring them");
+            return image;
+        }
+        let spacing = dots(self.spacing, model.spec().dpi);
+        d30::append_separator(&image, spacing, self.cut_marks)
+    }
+}
+
+/// Whole dots for a length from the command line, where bare numbers are in mm
+fn dots(length: d30::Length, dpi: u32) -> u32 {
+    length.dots(dpi, d30::Unit::Mm).round() as u32
+}
+
+// Layout of text with several lines, from newlines (`\n`) or wrapping
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsTextLayout {
+    /// `left`, `center` or `right`
+    #[arg(long)]
+    #[arg(default_value = "center")]
+    align: d30::Align,
+    /// `top`, `middle`, `bottom` or `baseline`, which centers capitals and ignores descenders
+    #[arg(long)]
+    #[arg(default_value = "middle")]
+    valign: d30::VerticalAlign,
+    /// Fixed position of the first line's baseline below the top margin, like `6mm`,
+    /// so every label in a series has its text on the same line
+    #[arg(long)]
+    baseline: Option<d30::Length>,
+    /// Break lines between words so they fit the label
+    #[arg(long)]
+    word_wrap: bool,
+    /// Distance between lines, as a multiple of the font's line height
+    #[arg(long)]
+    #[arg(default_value = "1.0")]
+    line_spacing: f32,
+}
+
+impl ArgsTextLayout {
+    fn layout(&self) -> d30::TextLayout {
+        d30::TextLayout {
+            align: self.align,
+            vertical_align: self.valign,
+            baseline: self.baseline,
+            wrap: self.word_wrap,
+            line_spacing: self.line_spacing,
+        }
+    }
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsOrientation {
+    /// Turn the content clockwise by `0`, `90`, `180` or `270` degrees
+    #[arg(long)]
+    #[arg(default_value = "0")]
+    rotate: d30::Rotation,
+    /// `horizontal` for labels read from the back, e.g. through glass, `vertical` or `both`
+    #[arg(long)]
+    #[arg(default_value = "none")]
+    mirror: d30::Mirror,
+}
+
+impl ArgsOrientation {
+    fn orientation(&self) -> d30::Orientation {
+        d30::Orientation {
+            rotation: self.rotate,
+            mirror: self.mirror,
+        }
+    }
+}
+
+// Cable flag ("fruit") layout options
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsFlag {
+    /// Print a cable flag: the text on both ends, with a zone in between that wraps around the cable
+    #[arg(long)]
+    flag: bool,
+    /// Cable diameter, which decides the length of the wrap zone. Bare numbers are in mm
+    #[arg(long)]
+    #[arg(default_value = "6")]
+    cable_diameter: d30::Length,
+    /// Wrap zone length, instead of deriving it from `--cable-diameter`. Bare numbers are in mm
+    #[arg(long)]
+    wrap: Option<d30::Length>,
+    /// `duplicate` the text on the second half, or `rotate` it upside down
+    #[arg(long)]
+    #[arg(default_value = "duplicate")]
+    flag_text: d30::FlagText,
+    /// `blank`, `hatch` or `dots`
+    #[arg(long)]
+    #[arg(default_value = "blank")]
+    wrap_fill: d30::WrapFill,
+}
+
+impl ArgsFlag {
+    fn layout(&self, model: d30::Model) -> Option<d30::FlagLayout> {
+        let diameter = self.cable_diameter;
+        let wrap = self.wrap.unwrap_or(d30::Length {
+            value: diameter.value * std::f32::consts::PI,
+            ..diameter
+        });
+        self.flag.then(|| d30::FlagLayout {
+            wrap_length: dots(wrap, model.spec().dpi),
+            text: self.flag_text,
+            wrap_fill: self.wrap_fill,
+        })
+    }
+}
+
+// Connection retry options, shared by every command that talks to a printer
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsRetry {
     #[arg(long)]
     #[arg(default_value = "10")]
     max_retries: usize,
-    /// Retry wait in seconds
-    #[arg(long)]
+    /// Retry wait in seconds. Doubles after every failed attempt, up to `--max-retry-wait`
+    #[arg(long, value_parser = parse_seconds)]
     #[arg(default_value = "1")]
-    retry_wait: f32,
+    retry_wait: Duration,
+    /// Longest retry wait in seconds
+    #[arg(long, value_parser = parse_seconds)]
+    #[arg(default_value = "10")]
+    max_retry_wait: Duration,
+    /// Give up on a single connection attempt after this many seconds. 0 to wait indefinitely
+    #[arg(long, value_parser = parse_seconds)]
+    #[arg(default_value = "15")]
+    connect_timeout: Duration,
+    /// Give up on a stalled write after this many seconds. 0 to wait indefinitely
+    #[arg(long, value_parser = parse_seconds)]
+    #[arg(default_value = "10")]
+    write_timeout: Duration,
+}
+
+impl ArgsRetry {
+    fn policy(&self) -> d30::RetryPolicy {
+        let timeout = |duration: Duration| (!duration.is_zero()).then_some(duration);
+        d30::RetryPolicy {
+            max_retries: self.max_retries,
+            initial_delay: self.retry_wait,
+            max_delay: self.max_retry_wait,
+            connect_timeout: timeout(self.connect_timeout),
+            write_timeout: timeout(self.write_timeout),
+            ..Default::default()
+        }
+    }
+}
+
+/// A non-negative number of seconds, fractions allowed
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse::<f32>()
+        .ok()
+        .and_then(|seconds| Duration::try_from_secs_f32(seconds).ok())
+        .ok_or_else(|| {
+            format!(
+                "Invalid duration: {}. Expected a non-negative number of seconds",
+                s
+            )
+        })
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct ArgsQueue {
+    #[command(subcommand)]
+    command: QueueCommand,
+}
+
+#[derive(Debug, Subcommand, Clone)]
+enum QueueCommand {
+    /// List queued jobs, oldest first
+    List,
+    /// Try to print a single queued job
+    Retry {
+        id: String,
+        #[command(flatten)]
+        retry: ArgsRetry,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Try to print every queued job
+    Flush {
+        #[command(flatten)]
+        retry: ArgsRetry,
+        #[arg(long)]
+        dry_run: bool,
+        /// Keep flushing, waiting this many seconds between rounds, until the queue is empty
+        #[arg(short, long, value_parser = parse_seconds)]
+        wait: Option<Duration>,
+    },
+    /// Remove jobs from the queue without printing them
+    Drop {
+        #[arg(required_unless_present = "all")]
+        id: Option<String>,
+        #[arg(long)]
+        all: bool,
+    },
 }
 
 // ---------------------
@@ -83,6 +590,8 @@ struct Config {
     dry_run: Option<bool>,
     enable_preview: Option<bool>,
     preview: Option<PreviewType>,
+    /// Queue labels that couldn't be printed, as if `--queue` was always passed
+    queue_on_failure: Option<bool>,
     d30_config: Option<d30::D30Config>,
 }
 
@@ -98,7 +607,7 @@ impl Config {
             .place_config_file("phomemo-cli-config.toml")
             .context(CouldNotPlaceConfigFileSnafu)?;
         let contents = fs::read_to_string(config_path).context(CouldNotReadFileSnafu)?;
-        Ok(toml::from_str(contents.as_str()).context(CouldNotParseTOMLSnafu)?)
+        toml::from_str(contents.as_str()).context(CouldNotParseTOMLSnafu)
     }
 }
 
@@ -271,10 +780,13 @@ fn get_addr(config: &mut Config, user_maybe_addr: Option<String>) -> Result<MacA
         }
         // No address on CLI, but there IS a config!
         // Try to resolve from config
-        (Option::None, Ok(config)) => {
+        (Option::None, Ok(d30_config)) => {
             info!("No address on CLI, but we have a config. Will attempt to identify default target from config.");
-            match config.resolve_default() {
-                Ok(addr) => Ok(addr),
+            match d30_config.resolve_default() {
+                Ok(addr) => {
+                    config.d30_config = Some(d30_config);
+                    Ok(addr)
+                }
                 Err(e) => {
                     error!("No address specified on command line or config.\nNo way to know what device we are targeting. This is a critical failure.");
                     Err(e).context(D30LibSnafu)
@@ -289,6 +801,50 @@ fn get_addr(config: &mut Config, user_maybe_addr: Option<String>) -> Result<MacA
     }
 }
 
+/// The model given on the command line, or else the one configured for the device.
+/// Call after `get_addr`, which loads the device config.
+fn get_model(config: &Config, addr: MacAddr6, user_model: Option<d30::Model>) -> d30::Model {
+    user_model
+        .or_else(|| {
+            config
+                .d30_config
+                .as_ref()
+                .and_then(|d30_config| d30_config.device(addr))
+                .map(|device| device.model)
+        })
+        .unwrap_or_default()
+}
+
+/// The calibration offset configured for the device, in mm along and across the label.
+/// Call after `get_addr`, which loads the device config.
+fn get_offset(config: &Config, addr: MacAddr6) -> (f32, f32) {
+    config
+        .d30_config
+        .as_ref()
+        .and_then(|d30_config| d30_config.device(addr))
+        .map(|device| (device.offset_x_mm, device.offset_y_mm))
+        .unwrap_or_default()
+}
+
+/// The media given on the command line, or else the one configured for the device, or the model's default
+fn get_media(
+    config: &Config,
+    addr: MacAddr6,
+    model: d30::Model,
+    user_media: Option<&str>,
+) -> Result<d30::Media, CLIError> {
+    let d30_config = config.d30_config.clone().unwrap_or_default();
+    let name = user_media
+        .map(str::to_string)
+        .or_else(|| d30_config.device(addr).and_then(|device| device.media));
+    let media = match name {
+        Some(name) => d30_config.resolve_media(&name).context(D30LibSnafu)?,
+        None => model.spec().default_media(),
+    };
+    model.spec().check_media(&media).context(D30LibSnafu)?;
+    Ok(media)
+}
+
 #[derive(Debug, Snafu)]
 enum CLIError {
     #[snafu(display("D30 library error"))]
@@ -296,9 +852,6 @@ enum CLIError {
     #[snafu(display("Failed to prompt user in interactive mode"))]
     FailedToPromptUser { source: InquireError },
 
-    #[snafu(display("Error while attempting task `{task}` in bluetooth backend: {source}"))]
-    BluetoothBackend { source: BtError, task: String },
-
     #[snafu(display("IO error while attempting to execute task: {task}"))]
     IOError {
         task: String,
@@ -316,6 +869,8 @@ enum CLIError {
     CouldNotReadFile { source: io::Error },
     #[snafu(display("Failed to serialize TOML D30 config"))]
     CouldNotParseTOML { source: toml::de::Error },
+    #[snafu(display("Failed to serialize TOML"))]
+    CouldNotSerializeTOML { source: toml::ser::Error },
 
     #[snafu(display("Could not parse MAC address: {address}"))]
     CouldNotParseMacAddr { source: ParseError, address: String },
@@ -331,30 +886,169 @@ enum CLIError {
 
     #[snafu(display("Parent directory missing while performing task: {task}"))]
     ParentDirectoryMissing { task: String },
+
+    #[snafu(display("Filesystem watcher error while attempting task: {task}"))]
+    WatcherError { task: String, source: notify::Error },
+
+    #[snafu(display("No queued job with ID {id}"))]
+    QueuedJobNotFound { id: String },
+}
+
+impl CLIError {
+    /// Whether retrying later might help, e.g. because the printer was asleep
+    fn is_recoverable(&self) -> bool {
+        match self {
+            CLIError::D30LibError { source } => source.is_recoverable(),
+            _ => false,
+        }
+    }
 }
 
 fn cmd_print(config: &mut Config, args: &ArgsPrintText) -> Result<(), CLIError> {
     trace!("Call: cmd_print");
     let mut args = args.to_owned();
-    let dry_run = config.dry_run.unwrap_or(false) || args.dry_run;
-    let show_preview = config.enable_preview.unwrap_or(false) || args.preview;
-    let addr = get_addr(config, args.device.clone())?;
+    let addr = get_addr(config, args.print.device.clone())?;
+    let model = get_model(config, addr, args.print.model);
+    let media = get_media(config, addr, model, args.print.media.as_deref())?;
+    debug!("Printing on {} media with a {}", media, model);
     debug!(
         "Generating image {} with scale {:?}",
         &args.text, &args.scale
     );
     let args_text = unescape::unescape(&args.text).expect("Failed to unescape input");
-    if args.minus_scale != 0.0 {
+    if args.minus_scale.is_some() {
+        warn!("--minus-scale is deprecated and ignored. Use --min-scale and --max-scale instead");
+    }
+    if let (Some(min), Some(max)) = (args.min_scale, args.max_scale) {
+        let dpi = model.spec().dpi;
+        if min.dots(dpi, d30::Unit::Px) > max.dots(dpi, d30::Unit::Px) {
+            Arguments::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("--min-scale {} is larger than --max-scale {}", min, max),
+                )
+                .exit();
+        }
+    }
+    if args.min_scale.is_some() || args.max_scale.is_some() {
         match &mut args.scale {
             D30Scale::Value(_) => {
-                warn!("Not sure why you gave me a minus scale when I'm not autoscaling. Ignoring value");
+                warn!("Not sure why you gave me a minimum or maximum scale when I'm not autoscaling. Ignoring value");
             }
-            D30Scale::Auto { ref mut minus } => {
-                *minus = args.minus_scale;
+            D30Scale::Auto { min, max } => {
+                *min = args.min_scale;
+                *max = args.max_scale;
             }
         }
     }
-    let image = d30::generate_image(&args_text, args.margins, args.scale).context(D30LibSnafu)?;
+    let orientation = args.orientation.orientation();
+    let canvas = orientation.canvas(args.tape.canvas(model, &media));
+    let layout = args.layout.layout();
+    let image = match args.flag.layout(model) {
+        _ if args.portrait => {
+            d30::generate_portrait_image(&args_text, &args.margins, args.scale, canvas)
+        }
+        Some(flag_layout) => d30::generate_flag_image(
+            &args_text,
+            &args.margins,
+            args.scale,
+            canvas,
+            &flag_layout,
+            &layout,
+        ),
+        None => d30::generate_text_image(&args_text, &args.margins, args.scale, canvas, &layout),
+    }
+    .context(D30LibSnafu)?;
+    let image = args.tape.separate(model, &media, orientation.apply(&image));
+    print_label(config, &args.print, addr, model, &media, &image, &args_text)
+}
+
+fn cmd_print_template(config: &mut Config, args: &ArgsPrintTemplate) -> Result<(), CLIError> {
+    trace!("Call: cmd_print_template");
+    let template = d30::template::Template::load(&args.template).context(D30LibSnafu)?;
+    let addr = get_addr(config, args.print.device.clone())?;
+    let model = get_model(config, addr, args.print.model);
+    let media = get_media(
+        config,
+        addr,
+        model,
+        args.print.media.as_deref().or(template.media.as_deref()),
+    )?;
+    debug!("Printing template on {} media with a {}", media, model);
+    let values: HashMap<String, String> = args.values.iter().cloned().collect();
+    let base_dir = args.template.parent().unwrap_or(Path::new("."));
+    let orientation = args.orientation.orientation();
+    let image = template
+        .render(
+            &values,
+            model.spec(),
+            orientation.canvas(args.tape.canvas(model, &media)),
+            base_dir,
+        )
+        .context(D30LibSnafu)?;
+    let image = args.tape.separate(model, &media, orientation.apply(&image));
+    let summary = format!("{} {:?}", args.template.display(), values);
+    print_label(config, &args.print, addr, model, &media, &image, &summary)
+}
+
+fn cmd_print_image(config: &mut Config, args: &ArgsPrintImage) -> Result<(), CLIError> {
+    trace!("Call: cmd_print_image");
+    let bytes = if args.image == Path::new("-") {
+        let mut bytes = Vec::new();
+        io::stdin().read_to_end(&mut bytes).context(IOSnafu {
+            task: "read image from stdin",
+        })?;
+        bytes
+    } else {
+        fs::read(&args.image).context(IOSnafu {
+            task: "read image file",
+        })?
+    };
+    let addr = get_addr(config, args.print.device.clone())?;
+    let model = get_model(config, addr, args.print.model);
+    let media = get_media(config, addr, model, args.print.media.as_deref())?;
+    debug!("Printing image on {} media with a {}", media, model);
+    let orientation = args.orientation.orientation();
+    let canvas = orientation.canvas(args.tape.canvas(model, &media));
+    let image = if is_svg(&args.image, &bytes) {
+        d30::rasterize_svg(&bytes, model.spec(), canvas, &args.processing.fit_options())
+            .context(D30LibSnafu)?
+    } else {
+        image::load_from_memory(&bytes).context(ImageSnafu { task: "load image" })?
+    };
+    let image = args.processing.fit(&image, canvas);
+    let image = args.processing.binarize(&image);
+    let image = args.tape.separate(model, &media, orientation.apply(&image));
+    let summary = args.image.display().to_string();
+    print_label(config, &args.print, addr, model, &media, &image, &summary)
+}
+
+/// SVG files go by their extension. On stdin, anything that looks like XML or is gzipped is taken for one
+fn is_svg(path: &Path, bytes: &[u8]) -> bool {
+    match path.extension().and_then(|extension| extension.to_str()) {
+        Some(extension) => matches!(extension.to_ascii_lowercase().as_str(), "svg" | "svgz"),
+        None => {
+            let start = bytes.trim_ascii_start();
+            start.starts_with(b"<") || start.starts_with(&[0x1f, 0x8b])
+        }
+    }
+}
+
+/// Show the preview if asked to, then print a rendered label, or queue it if the printer can't be reached.
+/// `summary` describes the label in the queue
+fn print_label(
+    config: &mut Config,
+    args: &ArgsPrint,
+    addr: MacAddr6,
+    model: d30::Model,
+    media: &d30::Media,
+    image: &DynamicImage,
+    summary: &str,
+) -> Result<(), CLIError> {
+    let dry_run = config.dry_run.unwrap_or(false) || args.dry_run;
+    let show_preview = config.enable_preview.unwrap_or(false) || args.preview;
+    // Anything that isn't 1-bit yet is thresholded, so the preview shows what is actually printed
+    let image = &d30::binarize(image, d30::Dither::Threshold, d30::Threshold::default());
     let mut preview_image = image.rotate90();
     preview_image.invert();
     if show_preview {
@@ -373,82 +1067,154 @@ fn cmd_print(config: &mut Config, args: &ArgsPrintText) -> Result<(), CLIError>
         }
     }
 
-    let mut socket: Option<BtSocket> = None;
-    println!("Connecting...");
-    'retry: for retries in 0.. {
-        info!("Retry #{}", retries);
-        if retries > args.max_retries {
-            error!("Failed to connect after {} retries!", args.max_retries);
-            exit(1);
-        }
-        if dry_run {
-            break 'retry;
+    let queue_on_failure = config.queue_on_failure.unwrap_or(false) || args.queue;
+    let mut printer = match open_printer(
+        addr,
+        model,
+        &args.retry.policy(),
+        dry_run,
+        args.capture.as_deref(),
+    ) {
+        Ok(printer) => printer,
+        Err(e) if queue_on_failure && e.is_recoverable() => {
+            let id = queue::enqueue(
+                addr,
+                model,
+                media.kind(),
+                image,
+                args.number_of_images,
+                summary,
+                &e,
+            )?;
+            println!("Printer unreachable. Queued as job {}", id);
+            return Ok(());
         }
-
-        let mut new_socket = match BtSocket::new(bluetooth_serial_port_async::BtProtocol::RFCOMM)
-            .context(BluetoothBackendSnafu {
-                task: "opening socket".to_string(),
-            }) {
-            Ok(v) => v,
-            Err(e) => {
-                error!(
-                    "Error while trying to open socket, on attempt #{}:\n{}",
-                    retries, e
-                );
-                continue 'retry;
-            }
-        };
-
-        if let Err(e) = new_socket.connect(BtAddr(addr.to_array())) {
-            error!(
-                "Error while trying to connect, on attempt #{}:\n{}",
-                retries, e
+        Err(e) => return Err(e),
+    };
+    printer.set_media_kind(media.kind());
+    let (offset_x, offset_y) = get_offset(config, addr);
+    printer.set_offset(offset_x, offset_y);
+    printer
+        .print(image, args.number_of_images.max(0) as u32)
+        .context(D30LibSnafu)?;
+    if queue_on_failure && !dry_run {
+        // The printer is evidently awake, so this is a good time to catch up. The label itself
+        // printed fine, so a queued job that fails again only stays queued
+        if let Err(e) = queue::flush_device(&mut printer) {
+            warn!(
+                "Could not print queued jobs, they stay queued: {}",
+                snafu::Report::from_error(e)
             );
-            continue 'retry;
         }
-
-        socket = Some(new_socket);
-        break 'retry;
     }
+    Ok(())
+}
 
-    debug!("Init connection");
-    if let Some(socket) = &mut socket {
-        socket
-            .write(d30::INIT_BASE_FLAT)
-            .map(|x| x)
-            .context(IOSnafu {
-                task: "send magic init bytes".to_string(),
-            })?;
+/// Connect to and initialize the printer at `addr`.
+/// Dry runs go through the same motions, but write into memory instead.
+fn open_printer(
+    addr: MacAddr6,
+    model: d30::Model,
+    policy: &d30::RetryPolicy,
+    dry_run: bool,
+    capture: Option<&Path>,
+) -> Result<D30Printer, CLIError> {
+    let mut printer = D30Printer::new(addr, policy.clone());
+    printer.set_model(model);
+    if let Some(capture) = capture {
+        printer.capture_to(CaptureWriter::create(capture, addr).context(D30LibSnafu)?);
     }
-    debug!("Extend output");
-
-    // Image must be send in chunks of 255 lines
-    let chunks = image.height() / 255;
-    for image_num in 0..args.number_of_images {
-        let mut output = d30::IMG_PRECURSOR.to_vec();
-
-        for chunk_num in 0..=chunks {
-            let chunk = image.clone().crop(0, chunk_num * 255, image.width(), 255);
-            debug!("Extend output");
-            output.extend(d30::pack_image(&chunk));
-            debug!("Write output to socket");
-            if let Some(socket) = &mut socket {
-                socket.write(output.as_slice()).context(IOSnafu {
-                    task: format!("write image #{}", image_num),
-                })?;
-            }
-            debug!("Flush socket");
-            if let Some(socket) = &mut socket {
-                socket.flush().context(IOSnafu {
-                    task: "flush socket".to_string(),
-                })?;
-            }
-            output.clear();
-        }
+    if dry_run {
+        printer.attach(Box::new(Cursor::new(Vec::new())))
+    } else {
+        println!("Connecting...");
+        printer.connect()
+    }
+    .context(D30LibSnafu)?;
+    Ok(printer)
+}
+
+fn cmd_calibrate(config: &mut Config, args: &ArgsCalibrate) -> Result<(), CLIError> {
+    trace!("Call: cmd_calibrate");
+    let dry_run = config.dry_run.unwrap_or(false) || args.dry_run;
+    let addr = get_addr(config, args.device.clone())?;
+    let model = get_model(config, addr, args.model);
+    let media = get_media(config, addr, model, args.media.as_deref())?;
+    let (offset_x, offset_y) = get_offset(config, addr);
+    let image = d30::generate_calibration_image(model.spec().canvas(&media));
+
+    let mut printer = open_printer(addr, model, &args.retry.policy(), dry_run, None)?;
+    printer.set_media_kind(media.kind());
+    // The pattern is printed with the current offset, so what is measured is what's left to correct
+    printer.set_offset(offset_x, offset_y);
+    printer.print(&image, 1).context(D30LibSnafu)?;
+    println!(
+        "Printed the calibration pattern, with the current offset of {}mm x {}mm",
+        offset_x, offset_y
+    );
+
+    let help = "Ticks are 1mm apart. Count them from the crosshair to both edges: half the difference is how far off it is";
+    let ask = |message| {
+        inquire::CustomType::<f32>::new(message)
+            .with_default(0.0)
+            .with_help_message(help)
+            .prompt()
+            .context(FailedToPromptUserSnafu)
+    };
+    let measured_x = ask("How many mm towards the end of the label is the vertical line? Negative if it's towards the start")?;
+    let measured_y = ask(
+        "How many mm below the middle of the label is the horizontal line? Negative if it's above",
+    )?;
+    let (offset_x, offset_y) = (offset_x - measured_x, offset_y - measured_y);
+    if dry_run {
+        println!(
+            "Dry run. The new offset would be {}mm x {}mm",
+            offset_x, offset_y
+        );
+        return Ok(());
     }
+    let path = d30::D30Config::save_offset(addr, offset_x, offset_y).context(D30LibSnafu)?;
+    println!(
+        "Saved an offset of {}mm x {}mm for {} to {}",
+        offset_x,
+        offset_y,
+        addr,
+        path.display()
+    );
     Ok(())
 }
 
+fn cmd_replay(config: &mut Config, args: &ArgsReplay) -> Result<(), CLIError> {
+    trace!("Call: cmd_replay");
+    let addr = get_addr(config, args.device.clone())?;
+    let file = fs::File::open(&args.file).context(IOSnafu {
+        task: "open capture file",
+    })?;
+    let records = d30::capture::read_capture(io::BufReader::new(file)).context(D30LibSnafu)?;
+    println!("Connecting...");
+    let mut socket = d30::connect(addr, &args.retry.policy()).context(D30LibSnafu)?;
+    d30::capture::replay(&mut socket, &records, !args.no_delay).context(D30LibSnafu)?;
+    println!("Replayed {} records", records.len());
+    Ok(())
+}
+
+fn cmd_models() {
+    for model in d30::Model::ALL {
+        let spec = model.spec();
+        let media: Vec<String> = spec.media.iter().map(|size| size.to_string()).collect();
+        println!(
+            "{}\t{}\t{} dots ({:.0}mm) at {} dpi\t{}\tmedia: {}",
+            model,
+            spec.support,
+            spec.width_dots,
+            spec.width_dots as f32 * 25.4 / spec.dpi as f32,
+            spec.dpi,
+            spec.transport,
+            media.join(", ")
+        );
+    }
+}
+
 #[snafu::report]
 #[tokio::main]
 async fn main() -> Result<(), CLIError> {
@@ -477,7 +1243,31 @@ async fn main() -> Result<(), CLIError> {
 
     match &args.command {
         Commands::PrintText(args) => {
-            cmd_print(&mut config, &args)?;
+            cmd_print(&mut config, args)?;
+        }
+        Commands::PrintTemplate(args) => {
+            cmd_print_template(&mut config, args)?;
+        }
+        Commands::PrintImage(args) => {
+            cmd_print_image(&mut config, args)?;
+        }
+        Commands::Watch(args) => {
+            watch::cmd_watch(&mut config, args)?;
+        }
+        Commands::Queue(args) => {
+            queue::cmd_queue(args)?;
+        }
+        Commands::Replay(args) => {
+            cmd_replay(&mut config, args)?;
+        }
+        Commands::Models => {
+            cmd_models();
+        }
+        Commands::Probe(args) => {
+            probe::cmd_probe(&mut config, args)?;
+        }
+        Commands::Calibrate(args) => {
+            cmd_calibrate(&mut config, args)?;
         }
     }
 
@@ -0,0 +1,154 @@
+use std::{
+    io::{self, BufRead, Cursor, Write},
+    path::PathBuf,
+    time::Instant,
+};
+
+use d30::{protocol, D30Error, Model};
+use log::trace;
+use snafu::ResultExt;
+
+use crate::{
+    get_addr, get_model, open_printer, queue::now, ArgsProbe, CLIError, Config,
+    CouldNotGetXDGPathSnafu, IOSnafu,
+};
+
+const HELP: &str = "\
+Type bytes as hex (e.g. `1f1111`, spaces allowed) to send them, and see what comes back.
+    read       wait for more response bytes
+    reconnect  connect again, e.g. after the printer went to sleep
+    help       show this message
+    quit       end the session";
+
+/// Where sessions are logged unless `--log` is given
+fn default_log_path() -> Result<PathBuf, CLIError> {
+    let phomemo_lib_path =
+        xdg::BaseDirectories::with_prefix("phomemo-library").context(CouldNotGetXDGPathSnafu)?;
+    let dir = phomemo_lib_path
+        .create_state_directory("probe")
+        .context(IOSnafu {
+            task: "create probe log directory",
+        })?;
+    Ok(dir.join(format!("{}.cap", now().as_secs())))
+}
+
+pub fn cmd_probe(config: &mut Config, args: &ArgsProbe) -> Result<(), CLIError> {
+    trace!("Call: cmd_probe");
+    let dry_run = config.dry_run.unwrap_or(false) || args.dry_run;
+    let addr = get_addr(config, args.device.clone())?;
+    let model = get_model(config, addr, args.model);
+    let log_path = match &args.log {
+        Some(path) => path.clone(),
+        None => default_log_path()?,
+    };
+    // The session log is a regular capture, so interesting sessions can be replayed later
+    let mut printer = open_printer(addr, model, &args.retry.policy(), dry_run, Some(&log_path))?;
+    println!(
+        "Connected to {}. Logging session to {}",
+        addr,
+        log_path.display()
+    );
+    announce_init(model);
+    println!("{}", HELP);
+
+    let start = Instant::now();
+    let stamp = || format!("[{:8.3}s]", start.elapsed().as_secs_f64());
+    let mut stdout = io::stdout();
+    let mut lines = io::stdin().lock().lines();
+    loop {
+        print!("probe> ");
+        stdout.flush().context(IOSnafu {
+            task: "flush prompt",
+        })?;
+        let line = match lines.next() {
+            Some(line) => line.context(IOSnafu {
+                task: "read command",
+            })?,
+            None => break,
+        };
+        let line = line.trim();
+        match line {
+            "" => continue,
+            "quit" | "exit" => break,
+            "help" | "?" => {
+                println!("{}", HELP);
+                continue;
+            }
+            "reconnect" => {
+                let result = if dry_run {
+                    printer.attach(Box::new(Cursor::new(Vec::new())))
+                } else {
+                    println!("Connecting...");
+                    printer.connect()
+                };
+                match result {
+                    Ok(()) => {
+                        println!("Connected to {}", addr);
+                        announce_init(model);
+                    }
+                    Err(e) => report(&e),
+                }
+                continue;
+            }
+            "read" => {}
+            _ => {
+                let digits: String = line.chars().filter(|c| !c.is_whitespace()).collect();
+                let bytes = match hex::decode(digits.trim_start_matches("0x")) {
+                    Ok(bytes) => bytes,
+                    Err(e) => {
+                        println!("Not sent, invalid hex: {}", e);
+                        continue;
+                    }
+                };
+                if let Err(e) = printer.send_raw(&bytes) {
+                    report(&e);
+                    continue;
+                }
+                for decoded in protocol::decode(&bytes) {
+                    println!("{} > {}", stamp(), decoded);
+                }
+            }
+        }
+
+        let response = match printer.receive() {
+            Ok(response) => response,
+            Err(e) => {
+                report(&e);
+                continue;
+            }
+        };
+        if response.is_empty() {
+            println!("{} (no response)", stamp());
+        }
+        for decoded in protocol::decode(&response) {
+            println!("{} < {}", stamp(), decoded);
+        }
+    }
+    println!("Session logged to {}", log_path.display());
+    Ok(())
+}
+
+/// Show the init sequence the printer got when connecting, since its responses come in with the first command
+fn announce_init(model: Model) {
+    let init = model.spec().init;
+    if init.is_empty() {
+        return;
+    }
+    println!("Sent the {} init sequence:", model);
+    for decoded in protocol::decode(init) {
+        println!("  > {}", decoded);
+    }
+}
+
+/// Errors end up in the session instead of ending it. A failed read or write drops the connection
+fn report(error: &D30Error) {
+    println!("{}", snafu::Report::from_error(error));
+    if matches!(
+        error,
+        D30Error::CouldNotWrite { .. }
+            | D30Error::CouldNotRead { .. }
+            | D30Error::NotConnected { .. }
+    ) {
+        println!("Printer disconnected. Type `reconnect` to connect again");
+    }
+}
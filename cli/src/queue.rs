@@ -0,0 +1,366 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::PathBuf,
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use advmac::MacAddr6;
+use d30::{D30Printer, MediaKind, Model};
+use image::DynamicImage;
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
+use snafu::ResultExt;
+
+use crate::{
+    ArgsQueue, CLIError, CouldNotGetXDGPathSnafu, CouldNotParseTOMLSnafu,
+    CouldNotSerializeTOMLSnafu, D30LibSnafu, IOSnafu, ImageSnafu, QueueCommand,
+    QueuedJobNotFoundSnafu,
+};
+
+const JOB_FILE: &str = "job.toml";
+const IMAGE_FILE: &str = "label.png";
+
+/// Options needed to replay a label that couldn't be printed.
+/// Stored as `job.toml` next to the already rendered `label.png`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueuedJob {
+    pub device: MacAddr6,
+    #[serde(default)]
+    pub model: Model,
+    #[serde(default)]
+    pub media_kind: MediaKind,
+    pub copies: i32,
+    /// Human readable summary for `queue list`, e.g. the label text
+    pub summary: String,
+    /// Seconds since the Unix epoch
+    pub queued_at: u64,
+    pub attempts: u32,
+    pub last_error: Option<String>,
+}
+
+fn queue_dir() -> Result<PathBuf, CLIError> {
+    let phomemo_lib_path =
+        xdg::BaseDirectories::with_prefix("phomemo-library").context(CouldNotGetXDGPathSnafu)?;
+    phomemo_lib_path
+        .create_state_directory("queue")
+        .context(IOSnafu {
+            task: "create queue directory",
+        })
+}
+
+pub fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Store a rendered label so it can be printed once the printer is reachable again.
+/// Returns the ID of the new job.
+pub fn enqueue(
+    device: MacAddr6,
+    model: Model,
+    media_kind: MediaKind,
+    image: &DynamicImage,
+    copies: i32,
+    summary: &str,
+    error: &CLIError,
+) -> Result<String, CLIError> {
+    let id = create_job_dir(now().as_millis())?;
+    let job_dir = queue_dir()?.join(&id);
+    image.save(job_dir.join(IMAGE_FILE)).context(ImageSnafu {
+        task: "save queued label",
+    })?;
+    let job = QueuedJob {
+        device,
+        model,
+        media_kind,
+        copies,
+        summary: summary.to_string(),
+        queued_at: now().as_secs(),
+        attempts: 1,
+        last_error: Some(error.to_string()),
+    };
+    write_job(&id, &job)?;
+    Ok(id)
+}
+
+/// Make the directory of a job queued at `millis`, returning its ID
+fn create_job_dir(millis: u128) -> Result<String, CLIError> {
+    let queue_dir = queue_dir()?;
+    let mut id = millis.to_string();
+    // Labels queued within the same millisecond, e.g. by parallel prints, get a suffix
+    for suffix in 1.. {
+        match fs::create_dir(queue_dir.join(&id)) {
+            Ok(()) => break,
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => id = format!("{}-{}", millis, suffix),
+            Err(e) => {
+                return Err(e).context(IOSnafu {
+                    task: "create job directory",
+                })
+            }
+        }
+    }
+    Ok(id)
+}
+
+fn write_job(id: &str, job: &QueuedJob) -> Result<(), CLIError> {
+    let contents = toml::to_string(job).context(CouldNotSerializeTOMLSnafu)?;
+    fs::write(queue_dir()?.join(id).join(JOB_FILE), contents).context(IOSnafu {
+        task: "write job file",
+    })
+}
+
+fn read_job(id: &str) -> Result<QueuedJob, CLIError> {
+    let path = queue_dir()?.join(id).join(JOB_FILE);
+    if !path.exists() {
+        return QueuedJobNotFoundSnafu { id }.fail();
+    }
+    let contents = fs::read_to_string(path).context(IOSnafu {
+        task: "read job file",
+    })?;
+    toml::from_str(&contents).context(CouldNotParseTOMLSnafu)
+}
+
+/// All queued job IDs, oldest first
+pub fn job_ids() -> Result<Vec<String>, CLIError> {
+    let mut ids: Vec<String> = fs::read_dir(queue_dir()?)
+        .context(IOSnafu {
+            task: "list queue directory",
+        })?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().join(JOB_FILE).exists())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect();
+    ids.sort_by_key(|id| queue_order(id));
+    Ok(ids)
+}
+
+/// IDs are the milliseconds since the Unix epoch, with a `-<n>` suffix for jobs queued in the same millisecond
+fn queue_order(id: &str) -> (u128, u32) {
+    let (millis, suffix) = id.split_once('-').unwrap_or((id, "0"));
+    (
+        millis.parse().unwrap_or(u128::MAX),
+        suffix.parse().unwrap_or(u32::MAX),
+    )
+}
+
+fn drop_job(id: &str) -> Result<(), CLIError> {
+    let job_dir = queue_dir()?.join(id);
+    if !job_dir.join(JOB_FILE).exists() {
+        return QueuedJobNotFoundSnafu { id }.fail();
+    }
+    fs::remove_dir_all(job_dir).context(IOSnafu {
+        task: "remove job directory",
+    })
+}
+
+fn print_job(printer: &mut D30Printer, id: &str) -> Result<(), CLIError> {
+    let job = read_job(id)?;
+    let image = image::open(queue_dir()?.join(id).join(IMAGE_FILE)).context(ImageSnafu {
+        task: "load queued label",
+    })?;
+    printer.set_media_kind(job.media_kind);
+    printer
+        .print(&image, job.copies.max(0) as u32)
+        .context(D30LibSnafu)
+}
+
+/// Print a queued job over an already open connection, removing it from the queue on success.
+pub fn send_job(printer: &mut D30Printer, id: &str) -> Result<(), CLIError> {
+    print_job(printer, id)?;
+    drop_job(id)
+}
+
+/// Connect to the job's printer and print it. On failure, the attempt is recorded and the job stays queued.
+/// Dry runs leave the job queued either way.
+fn retry_job(id: &str, policy: &d30::RetryPolicy, dry_run: bool) -> Result<(), CLIError> {
+    let mut job = read_job(id)?;
+    info!("Retrying job {} for {}", id, job.device);
+    let device = d30::D30Config::read_d30_config()
+        .ok()
+        .and_then(|d30_config| d30_config.device(job.device));
+    let result = crate::open_printer(job.device, job.model, policy, dry_run, None).and_then(
+        |mut printer| {
+            if let Some(device) = &device {
+                printer.set_offset(device.offset_x_mm, device.offset_y_mm);
+            }
+            if dry_run {
+                print_job(&mut printer, id)
+            } else {
+                send_job(&mut printer, id)
+            }
+        },
+    );
+    if let Err(e) = &result {
+        record_failure(id, &mut job, e)?;
+    }
+    result
+}
+
+fn record_failure(id: &str, job: &mut QueuedJob, error: &CLIError) -> Result<(), CLIError> {
+    job.attempts += 1;
+    job.last_error = Some(error.to_string());
+    write_job(id, job)
+}
+
+/// Print any other jobs queued for this printer while we already have a working connection to it.
+/// Stops at the first job that fails, which stays queued with the attempt recorded.
+pub fn flush_device(printer: &mut D30Printer) -> Result<(), CLIError> {
+    for id in job_ids()? {
+        let mut job = read_job(&id)?;
+        if job.device != printer.addr() {
+            continue;
+        }
+        println!("Printing queued job {}", id);
+        if let Err(e) = send_job(printer, &id) {
+            record_failure(&id, &mut job, &e)?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Try every queued job once. Returns the number of jobs still queued.
+fn flush(policy: &d30::RetryPolicy, dry_run: bool) -> Result<usize, CLIError> {
+    for id in job_ids()? {
+        match retry_job(&id, policy, dry_run) {
+            Ok(()) if dry_run => {
+                println!("Dry run of queued job {} succeeded. It stays queued", id)
+            }
+            Ok(()) => println!("Printed queued job {}", id),
+            Err(e) => warn!("Job {} is still queued: {}", id, e),
+        }
+    }
+    Ok(job_ids()?.len())
+}
+
+pub fn cmd_queue(args: &ArgsQueue) -> Result<(), CLIError> {
+    trace!("Call: cmd_queue");
+    match &args.command {
+        QueueCommand::List => {
+            for id in job_ids()? {
+                let job = read_job(&id)?;
+                println!(
+                    "{}\t{}\t{} cop{}\t{} attempt{}\t{}",
+                    id,
+                    job.device,
+                    job.copies,
+                    if job.copies == 1 { "y" } else { "ies" },
+                    job.attempts,
+                    if job.attempts == 1 { "" } else { "s" },
+                    job.summary
+                );
+                if let Some(last_error) = &job.last_error {
+                    println!("\tlast error: {}", last_error);
+                }
+            }
+        }
+        QueueCommand::Retry { id, retry, dry_run } => {
+            retry_job(id, &retry.policy(), *dry_run)?;
+            println!("Printed queued job {}", id);
+        }
+        QueueCommand::Flush {
+            retry,
+            dry_run,
+            wait,
+        } => loop {
+            let remaining = flush(&retry.policy(), *dry_run)?;
+            match (remaining, wait) {
+                (0, _) => {
+                    println!("Queue is empty");
+                    break;
+                }
+                (_, Some(wait)) if !dry_run => {
+                    debug!(
+                        "{} jobs remaining, waiting {} seconds",
+                        remaining,
+                        wait.as_secs_f32()
+                    );
+                    thread::sleep(*wait);
+                }
+                (_, _) => {
+                    println!("{} job(s) still queued", remaining);
+                    break;
+                }
+            }
+        },
+        QueueCommand::Drop { id, all } => {
+            let ids = if *all {
+                job_ids()?
+            } else {
+                id.iter().cloned().collect()
+            };
+            for id in ids {
+                drop_job(&id)?;
+                println!("Dropped job {}", id);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use image::RgbImage;
+
+    fn job(summary: &str) -> QueuedJob {
+        QueuedJob {
+            device: MacAddr6::nil(),
+            model: Model::D30,
+            media_kind: MediaKind::Gaps,
+            copies: 1,
+            summary: summary.to_string(),
+            queued_at: 0,
+            attempts: 1,
+            last_error: None,
+        }
+    }
+
+    // The only test that touches the queue, as the state dir is process wide
+    #[test]
+    fn enqueue_list_and_drop() {
+        let state_dir = std::env::temp_dir().join(format!("d30-queue-test-{}", std::process::id()));
+        std::env::set_var("XDG_STATE_HOME", &state_dir);
+        assert_eq!(job_ids().unwrap(), Vec::<String>::new());
+
+        // Two jobs in the same millisecond, as if printed in parallel
+        let first = create_job_dir(1000).unwrap();
+        let second = create_job_dir(1000).unwrap();
+        assert_eq!((first.as_str(), second.as_str()), ("1000", "1000-1"));
+        write_job(&second, &job("second")).unwrap();
+        write_job(&first, &job("first")).unwrap();
+        let error = CLIError::BinaryUnspecified;
+        let image = DynamicImage::from(RgbImage::new(8, 8));
+        let latest = enqueue(
+            MacAddr6::nil(),
+            Model::D30,
+            MediaKind::Gaps,
+            &image,
+            2,
+            "latest",
+            &error,
+        )
+        .unwrap();
+        assert_eq!(
+            job_ids().unwrap(),
+            [first.clone(), second.clone(), latest.clone()]
+        );
+        let queued = read_job(&latest).unwrap();
+        assert_eq!((queued.copies, queued.summary.as_str()), (2, "latest"));
+        assert_eq!(queued.last_error, Some(error.to_string()));
+
+        drop_job(&second).unwrap();
+        assert_eq!(job_ids().unwrap(), [first.clone(), latest]);
+        assert_eq!(read_job(&first).unwrap().summary, "first");
+        assert!(matches!(
+            drop_job(&second),
+            Err(CLIError::QueuedJobNotFound { .. })
+        ));
+
+        fs::remove_dir_all(state_dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+    sync::mpsc,
+};
+
+use d30::{capture::CaptureWriter, Canvas, D30Printer, D30Scale, Media};
+use image::DynamicImage;
+use log::{debug, error, info, trace};
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind, RenameMode},
+    EventKind, RecursiveMode, Watcher,
+};
+use serde::Deserialize;
+use snafu::{ResultExt, Snafu};
+
+use crate::{get_addr, get_media, get_model, get_offset, ArgsWatch, CLIError, Config, D30LibSnafu};
+
+const DONE_DIR: &str = "done";
+const FAILED_DIR: &str = "failed";
+/// Anything else, like the `foo.txt.tmp` an editor writes before renaming it, is left alone
+const JOB_EXTENSIONS: [&str; 3] = ["txt", "png", "toml"];
+
+/// A label description dropped into the watch folder as `*.toml`.
+/// Unset fields fall back to the options `watch` was started with.
+#[derive(Debug, Deserialize)]
+struct LabelDescription {
+    text: String,
+    scale: Option<String>,
+    margins: Option<d30::Margins>,
+    copies: Option<i32>,
+}
+
+#[derive(Debug, Snafu)]
+pub enum WatchError {
+    #[snafu(display("Unsupported file type: {}", path.display()))]
+    UnsupportedFileType { path: PathBuf },
+
+    #[snafu(display("Could not read {}", path.display()))]
+    CouldNotReadJob {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[snafu(display("Could not decode image {}", path.display()))]
+    CouldNotDecodeImage {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+
+    #[snafu(display("Could not parse label description {}", path.display()))]
+    CouldNotParseDescription {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+
+    #[snafu(display("Invalid scale in label description: {message}"))]
+    InvalidScale { message: String },
+
+    #[snafu(display("Could not render label"))]
+    CouldNotRender { source: d30::D30Error },
+
+    #[snafu(display("Could not print label"))]
+    CouldNotPrint { source: d30::D30Error },
+}
+
+pub fn cmd_watch(config: &mut Config, args: &ArgsWatch) -> Result<(), CLIError> {
+    trace!("Call: cmd_watch");
+    let dry_run = config.dry_run.unwrap_or(false) || args.dry_run;
+    let addr = get_addr(config, args.device.clone())?;
+    let model = get_model(config, addr, args.model);
+    let media = get_media(config, addr, model, args.media.as_deref())?;
+    let canvas = args.tape.canvas(model, &media);
+    // One session for the whole watch. It connects on the first job, and reconnects after errors
+    let mut printer = D30Printer::new(addr, args.retry.policy());
+    printer.set_model(model);
+    printer.set_media_kind(media.kind());
+    let (offset_x, offset_y) = get_offset(config, addr);
+    printer.set_offset(offset_x, offset_y);
+    if let Some(capture) = &args.capture {
+        printer.capture_to(CaptureWriter::create(capture, addr).context(D30LibSnafu)?);
+    }
+    if dry_run {
+        printer
+            .attach(Box::new(Cursor::new(Vec::new())))
+            .context(D30LibSnafu)?;
+    }
+
+    for sub_dir in [DONE_DIR, FAILED_DIR] {
+        fs::create_dir_all(args.dir.join(sub_dir)).context(crate::IOSnafu {
+            task: format!("create `{}` directory", sub_dir),
+        })?;
+    }
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(tx).context(crate::WatcherSnafu {
+        task: "create watcher",
+    })?;
+    watcher
+        .watch(&args.dir, RecursiveMode::NonRecursive)
+        .context(crate::WatcherSnafu {
+            task: "watch directory",
+        })?;
+
+    // Anything dropped while we weren't running still gets printed
+    let mut pending: Vec<PathBuf> = fs::read_dir(&args.dir)
+        .context(crate::IOSnafu {
+            task: "list watch directory",
+        })?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file())
+        .collect();
+    pending.sort();
+    for path in pending {
+        process_file(args, canvas, &media, &mut printer, &path);
+    }
+
+    println!("Watching {}", args.dir.display());
+    for event in rx {
+        let event = event.context(crate::WatcherSnafu {
+            task: "receive event",
+        })?;
+        trace!("{:?}", event);
+        // Only act once the writer is done with the file, so we never print half a PNG
+        let finished = matches!(
+            event.kind,
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+                | EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        );
+        if !finished {
+            continue;
+        }
+        for path in event.paths {
+            if path.is_file() {
+                process_file(args, canvas, &media, &mut printer, &path);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn process_file(
+    args: &ArgsWatch,
+    canvas: Canvas,
+    media: &Media,
+    printer: &mut D30Printer,
+    path: &Path,
+) {
+    if path.parent() != Some(args.dir.as_path()) || !is_job(path) {
+        trace!("Ignoring {}", path.display());
+        return;
+    }
+    info!("Processing {}", path.display());
+    let result = render_job(args, canvas, path).and_then(|(image, copies)| {
+        let image = args.tape.separate(printer.model(), media, image);
+        printer
+            .print(&image, copies.max(0) as u32)
+            .context(CouldNotPrintSnafu)
+    });
+
+    let file_name = path.file_name().unwrap_or_default();
+    // A file we can't move or annotate is reported, but doesn't stop the watch
+    let moved = match result {
+        Ok(()) => {
+            println!("Printed {}", path.display());
+            move_to(path, &args.dir.join(DONE_DIR), file_name).map(|_| ())
+        }
+        Err(e) => {
+            error!("Failed to print {}: {}", path.display(), e);
+            move_to(path, &args.dir.join(FAILED_DIR), file_name).and_then(|target| {
+                let mut note = target.into_os_string();
+                note.push(".error.txt");
+                fs::write(&note, format!("{}\n", snafu::Report::from_error(e))).context(
+                    crate::IOSnafu {
+                        task: "write error note",
+                    },
+                )
+            })
+        }
+    };
+    if let Err(e) = moved {
+        error!("{}", snafu::Report::from_error(e));
+    }
+}
+
+/// Whether `path` is a job to print: not hidden, with one of the supported extensions
+fn is_job(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_none_or(|name| name.starts_with('.'));
+    let supported = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            JOB_EXTENSIONS
+                .iter()
+                .any(|job| extension.eq_ignore_ascii_case(job))
+        });
+    !hidden && supported
+}
+
+/// Render a dropped file to a label image, returning the number of copies to print.
+fn render_job(
+    args: &ArgsWatch,
+    canvas: Canvas,
+    path: &Path,
+) -> Result<(DynamicImage, i32), WatchError> {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(|extension| extension.to_ascii_lowercase());
+    match extension.as_deref() {
+        Some("txt") => {
+            let text = fs::read_to_string(path).context(CouldNotReadJobSnafu {
+                path: path.to_owned(),
+            })?;
+            let image =
+                d30::generate_image_sized(text.trim_end(), &args.margins, args.scale, canvas)
+                    .context(CouldNotRenderSnafu)?;
+            Ok((image, args.number_of_images))
+        }
+        Some("png") => {
+            let image = image::open(path).context(CouldNotDecodeImageSnafu {
+                path: path.to_owned(),
+            })?;
+            Ok((d30::fit_image_sized(&image, canvas), args.number_of_images))
+        }
+        Some("toml") => {
+            let contents = fs::read_to_string(path).context(CouldNotReadJobSnafu {
+                path: path.to_owned(),
+            })?;
+            let description: LabelDescription =
+                toml::from_str(&contents).context(CouldNotParseDescriptionSnafu {
+                    path: path.to_owned(),
+                })?;
+            debug!("{:?}", description);
+            let scale = match &description.scale {
+                Some(scale) => scale
+                    .parse::<D30Scale>()
+                    .map_err(|message| InvalidScaleSnafu { message }.build())?,
+                None => args.scale,
+            };
+            let image = d30::generate_image_sized(
+                &description.text,
+                &description.margins.unwrap_or(args.margins),
+                scale,
+                canvas,
+            )
+            .context(CouldNotRenderSnafu)?;
+            Ok((image, description.copies.unwrap_or(args.number_of_images)))
+        }
+        _ => UnsupportedFileTypeSnafu {
+            path: path.to_owned(),
+        }
+        .fail(),
+    }
+}
+
+/// Move `path` into `dir`, appending a counter to the name if it's already taken.
+fn move_to(path: &Path, dir: &Path, file_name: &std::ffi::OsStr) -> Result<PathBuf, CLIError> {
+    let mut target = dir.join(file_name);
+    let mut counter = 1;
+    while target.exists() {
+        let mut name = file_name.to_owned();
+        name.push(format!(".{}", counter));
+        target = dir.join(name);
+        counter += 1;
+    }
+    fs::rename(path, &target).context(crate::IOSnafu {
+        task: format!("move {} to {}", path.display(), dir.display()),
+    })?;
+    Ok(target)
+}
@@ -0,0 +1,116 @@
+//! Tone and filter adjustments for images, applied before they're dithered or thresholded.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{imageops, GrayImage};
+use imageproc::{edges::canny, filter::gaussian_blur_f32};
+
+/// Gradient thresholds for `Effect::Edges`
+const CANNY_LOW: f32 = 20.0;
+const CANNY_HIGH: f32 = 60.0;
+/// Blur radius for `Effect::Sketch`. Larger gives bolder strokes
+const SKETCH_SIGMA: f32 = 4.0;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Effect {
+    #[default]
+    #[display(fmt = "none")]
+    None,
+    /// Only the outlines, found with the Canny edge detector
+    #[display(fmt = "edges")]
+    Edges,
+    /// Pencil drawing look, which keeps some shading
+    #[display(fmt = "sketch")]
+    Sketch,
+}
+
+impl FromStr for Effect {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "edges" => Ok(Self::Edges),
+            "sketch" => Ok(Self::Sketch),
+            _ => Err(format!(
+                "Invalid effect: {}. Expected `none`, `edges` or `sketch`",
+                s
+            )),
+        }
+    }
+}
+
+/// Adjustments, in terms of the image as it looks on screen
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Adjustments {
+    /// Added to every pixel, from -255 to 255
+    pub brightness: i32,
+    /// In percent. Negative values reduce contrast
+    pub contrast: f32,
+    /// Above 1 lightens the midtones, below 1 darkens them
+    pub gamma: f32,
+    /// Unsharp mask radius in pixels. 0 to leave the image as it is
+    pub sharpen: f32,
+    pub invert: bool,
+    pub effect: Effect,
+}
+
+impl Default for Adjustments {
+    fn default() -> Self {
+        Self {
+            brightness: 0,
+            contrast: 0.0,
+            gamma: 1.0,
+            sharpen: 0.0,
+            invert: false,
+            effect: Effect::None,
+        }
+    }
+}
+
+impl Adjustments {
+    /// Apply the adjustments to a grayscale image, dark pixels being ink
+    pub fn apply(&self, image: &GrayImage) -> GrayImage {
+        let mut image = image.clone();
+        if self.brightness != 0 {
+            image = imageops::brighten(&image, self.brightness);
+        }
+        if self.contrast != 0.0 {
+            image = imageops::contrast(&image, self.contrast);
+        }
+        if self.gamma > 0.0 && self.gamma != 1.0 {
+            let lookup: Vec<u8> = (0..=255u8)
+                .map(|value| (255.0 * (value as f32 / 255.0).powf(1.0 / self.gamma)).round() as u8)
+                .collect();
+            for pixel in image.pixels_mut() {
+                pixel[0] = lookup[pixel[0] as usize];
+            }
+        }
+        if self.sharpen > 0.0 {
+            image = imageops::unsharpen(&image, self.sharpen, 0);
+        }
+        match self.effect {
+            Effect::None => {}
+            Effect::Edges => {
+                image = canny(&image, CANNY_LOW, CANNY_HIGH);
+                // Canny marks edges bright, but they're supposed to be ink
+                imageops::invert(&mut image);
+            }
+            Effect::Sketch => {
+                // Color dodge of the image with a blurred negative of itself
+                let mut negative = image.clone();
+                imageops::invert(&mut negative);
+                let blurred = gaussian_blur_f32(&negative, SKETCH_SIGMA);
+                for (pixel, blurred) in image.pixels_mut().zip(blurred.pixels()) {
+                    let dodged = pixel[0] as u32 * 255 / (256 - blurred[0] as u32);
+                    pixel[0] = dodged.min(255) as u8;
+                }
+            }
+        }
+        if self.invert {
+            imageops::invert(&mut image);
+        }
+        image
+    }
+}
@@ -0,0 +1,179 @@
+//! Barcodes for templates. Code 128 is simple enough to encode by hand,
+//! QR codes come from the `qrcode` crate.
+
+use image::{GrayImage, Luma};
+use qrcode::{Color, QrCode};
+use serde::{Deserialize, Serialize};
+
+use crate::{D30Error, InvalidBarcodeSnafu};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Symbology {
+    #[default]
+    Code128,
+    Qr,
+}
+
+/// Bar and space widths in modules, for every Code 128 symbol
+const CODE128_PATTERNS: [&[u8; 6]; 106] = [
+    b"212222", b"222122", b"222221", b"121223", b"121322", b"131222", b"122213", b"122312",
+    b"132212", b"221213", b"221312", b"231212", b"112232", b"122132", b"122231", b"113222",
+    b"123122", b"123221", b"223211", b"221132", b"221231", b"213212", b"223112", b"312131",
+    b"311222", b"321122", b"321221", b"312212", b"322112", b"322211", b"212123", b"212321",
+    b"232121", b"111323", b"131123", b"131321", b"112313", b"132113", b"132311", b"211313",
+    b"231113", b"231311", b"112133", b"112331", b"132131", b"113123", b"113321", b"133121",
+    b"313121", b"211331", b"231131", b"213113", b"213311", b"213131", b"311123", b"311321",
+    b"331121", b"312113", b"312311", b"332111", b"314111", b"221411", b"431111", b"111224",
+    b"111422", b"121124", b"121421", b"141122", b"141221", b"112214", b"112412", b"122114",
+    b"122411", b"142112", b"142211", b"241211", b"221114", b"413111", b"241112", b"134111",
+    b"111242", b"121142", b"121241", b"114212", b"124112", b"124211", b"411212", b"421112",
+    b"421211", b"212141", b"214121", b"412121", b"111143", b"111341", b"131141", b"114113",
+    b"114311", b"411113", b"411311", b"113141", b"114131", b"311141", b"411131", b"211412",
+    b"211214", b"211232",
+];
+const CODE128_STOP: &[u8; 7] = b"2331112";
+const CODE128_START_B: usize = 104;
+
+/// Modules of a Code 128 symbol in code set B, `true` for bars. No quiet zone is included
+fn code128_modules(data: &str) -> Result<Vec<bool>, D30Error> {
+    let mut symbols = vec![CODE128_START_B];
+    for c in data.chars() {
+        match c {
+            ' '..='\u{7f}' => symbols.push(c as usize - ' ' as usize),
+            _ => {
+                return InvalidBarcodeSnafu {
+                    message: format!("Code 128 can't encode {:?}", c),
+                }
+                .fail()
+            }
+        }
+    }
+    let checksum = symbols
+        .iter()
+        .enumerate()
+        .map(|(i, symbol)| i.max(1) * symbol)
+        .sum::<usize>()
+        % 103;
+    symbols.push(checksum);
+
+    let mut modules = Vec::new();
+    let widths = symbols
+        .iter()
+        .flat_map(|symbol| CODE128_PATTERNS[*symbol].iter())
+        .chain(CODE128_STOP.iter());
+    for (i, width) in widths.enumerate() {
+        let bar = i % 2 == 0;
+        modules.extend(std::iter::repeat_n(bar, (width - b'0') as usize));
+    }
+    Ok(modules)
+}
+
+/// Render `data` as ink (255) on a `width` by `height` dot bitmap.
+/// Modules are whole dots wide, so the barcode may come out smaller than the box.
+/// Fails if the box is too small for modules one dot wide, rather than cutting the code off
+pub(crate) fn render(
+    symbology: Symbology,
+    data: &str,
+    width: u32,
+    height: u32,
+) -> Result<GrayImage, D30Error> {
+    let mut image = GrayImage::new(width.max(1), height.max(1));
+    match symbology {
+        Symbology::Code128 => {
+            let modules = code128_modules(data)?;
+            if modules.len() as u32 > width {
+                return InvalidBarcodeSnafu {
+                    message: format!(
+                        "{:?} needs {} dots, but the barcode is only {} wide",
+                        data,
+                        modules.len(),
+                        width
+                    ),
+                }
+                .fail();
+            }
+            let module = width / modules.len() as u32;
+            for (i, _) in modules.iter().enumerate().filter(|(_, bar)| **bar) {
+                for x in (i as u32 * module)..((i as u32 + 1) * module).min(image.width()) {
+                    for y in 0..image.height() {
+                        image.put_pixel(x, y, Luma([255]));
+                    }
+                }
+            }
+        }
+        Symbology::Qr => {
+            let code = QrCode::new(data.as_bytes()).map_err(|e| {
+                InvalidBarcodeSnafu {
+                    message: e.to_string(),
+                }
+                .build()
+            })?;
+            let size = code.width() as u32;
+            if size > width.min(height) {
+                return InvalidBarcodeSnafu {
+                    message: format!(
+                        "{:?} needs {}x{} dots, but the barcode is only {}x{}",
+                        data, size, size, width, height
+                    ),
+                }
+                .fail();
+            }
+            let module = width.min(height) / size;
+            for (i, color) in code.to_colors().into_iter().enumerate() {
+                if color != Color::Dark {
+                    continue;
+                }
+                let (col, row) = (i as u32 % size, i as u32 / size);
+                for x in (col * module)..((col + 1) * module).min(image.width()) {
+                    for y in (row * module)..((row + 1) * module).min(image.height()) {
+                        image.put_pixel(x, y, Luma([255]));
+                    }
+                }
+            }
+        }
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Start, one symbol, checksum and the longer stop symbol
+    const CODE128_ONE_CHARACTER: u32 = 11 + 11 + 11 + 13;
+
+    #[test]
+    fn code128_module_count() {
+        assert_eq!(
+            code128_modules("A").unwrap().len() as u32,
+            CODE128_ONE_CHARACTER
+        );
+        assert!(code128_modules("é").is_err());
+    }
+
+    #[test]
+    fn code128_that_does_not_fit_fails() {
+        assert!(render(Symbology::Code128, "A", CODE128_ONE_CHARACTER, 10).is_ok());
+        let result = render(Symbology::Code128, "A", CODE128_ONE_CHARACTER - 1, 10);
+        assert!(matches!(result, Err(D30Error::InvalidBarcode { .. })));
+    }
+
+    #[test]
+    fn code128_modules_are_whole_dots() {
+        let image = render(Symbology::Code128, "A", CODE128_ONE_CHARACTER * 2 + 1, 4).unwrap();
+        // The start symbol opens with a bar two modules wide, then a space one module wide
+        let row: Vec<u8> = (0..8).map(|x| image.get_pixel(x, 0)[0]).collect();
+        assert_eq!(row, [255, 255, 255, 255, 0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn qr_that_does_not_fit_fails() {
+        // Short data fits a version 1 code, 21 modules square
+        assert!(render(Symbology::Qr, "hello", 21, 21).is_ok());
+        for (width, height) in [(20, 40), (40, 20)] {
+            let result = render(Symbology::Qr, "hello", width, height);
+            assert!(matches!(result, Err(D30Error::InvalidBarcode { .. })));
+        }
+    }
+}
@@ -0,0 +1,46 @@
+//! Test pattern for measuring how far off center a printer prints.
+
+use image::{DynamicImage, RgbImage};
+use imageproc::{drawing::draw_filled_rect_mut, rect::Rect};
+
+use crate::{Canvas, COLOR_BLACK};
+
+/// Ruler ticks stick out this far from the center lines, in mm. Every fifth tick is twice as long
+const TICK_MM: f32 = 0.75;
+
+/// Render a crosshair through the center of the canvas, with rulers along both lines that have
+/// a tick every mm, counted from the center. Comparing the ticks at both ends of the label shows
+/// how far the crosshair is off: half the difference
+pub fn generate_calibration_image(canvas: Canvas) -> DynamicImage {
+    let mut output = RgbImage::new(canvas.width, canvas.height);
+    let dots = |mm: f32| mm * canvas.dpi as f32 / 25.4;
+    let (center_x, center_y) = (canvas.width as i32 / 2, canvas.height as i32 / 2);
+    let mut fill = |x: i32, y: i32, width: u32, height: u32| {
+        draw_filled_rect_mut(
+            &mut output,
+            Rect::at(x, y).of_size(width.max(1), height.max(1)),
+            COLOR_BLACK,
+        )
+    };
+
+    // Crosshair, two dots thick so it survives printing
+    fill(0, center_y - 1, canvas.width, 2);
+    fill(center_x - 1, 0, 2, canvas.height);
+
+    let tick = |mm: i32| {
+        let length = if mm % 5 == 0 { TICK_MM * 2.0 } else { TICK_MM };
+        dots(length).round() as i32
+    };
+    let along = (canvas.width as f32 / dots(1.0) / 2.0) as i32;
+    for mm in (-along..=along).filter(|mm| *mm != 0) {
+        let x = center_x + dots(mm as f32).round() as i32;
+        fill(x, center_y - tick(mm), 1, 2 * tick(mm) as u32);
+    }
+    let across = (canvas.height as f32 / dots(1.0) / 2.0) as i32;
+    for mm in (-across..=across).filter(|mm| *mm != 0) {
+        let y = center_y + dots(mm as f32).round() as i32;
+        fill(center_x - tick(mm), y, 2 * tick(mm) as u32, 1);
+    }
+
+    DynamicImage::from(output).rotate270()
+}
@@ -0,0 +1,255 @@
+//! Plain text capture of everything exchanged with a printer, for debugging and bug reports.
+//!
+//! ```text
+//! # d30 capture v1
+//! # device 40-5B-A4-2F-05-46
+//! 0.000000 > 1f1138
+//! 0.000412 > 1f1124001b401d7630000c004001...
+//! 0.801233 < 1a0f0c
+//! ```
+//!
+//! Each record is the seconds since the capture started, a direction
+//! (`>` sent to the printer, `<` received from it) and the bytes as hex.
+//! The first line has to be the header shown above. Other lines starting with `#` are comments.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufWriter, Write},
+    path::Path,
+    thread,
+    time::{Duration, Instant},
+};
+
+use advmac::MacAddr6;
+use log::debug;
+use snafu::ResultExt;
+
+use crate::{
+    CouldNotReadCaptureSnafu, CouldNotWriteCaptureSnafu, CouldNotWriteSnafu, D30Error,
+    InvalidCaptureRecordSnafu, NotACaptureSnafu, Transport,
+};
+
+const HEADER: &str = "# d30 capture v1";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureRecord {
+    /// Time since the start of the capture
+    pub offset: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+/// Records traffic in the capture format. Attach one to a `D30Printer` with `D30Printer::capture_to`.
+pub struct CaptureWriter {
+    start: Instant,
+    out: Box<dyn Write>,
+}
+
+impl CaptureWriter {
+    pub fn new(mut out: Box<dyn Write>, device: MacAddr6) -> Result<Self, D30Error> {
+        writeln!(out, "{}\n# device {}", HEADER, device).context(CouldNotWriteCaptureSnafu)?;
+        Ok(Self {
+            start: Instant::now(),
+            out,
+        })
+    }
+
+    pub fn create(path: &Path, device: MacAddr6) -> Result<Self, D30Error> {
+        let file = File::create(path).context(CouldNotWriteCaptureSnafu)?;
+        Self::new(Box::new(BufWriter::new(file)), device)
+    }
+
+    pub fn record(&mut self, direction: Direction, bytes: &[u8]) -> Result<(), D30Error> {
+        self.record_at(self.start.elapsed(), direction, bytes)
+    }
+
+    /// Record with an explicit offset, e.g. when converting traffic captured elsewhere
+    pub fn record_at(
+        &mut self,
+        offset: Duration,
+        direction: Direction,
+        bytes: &[u8],
+    ) -> Result<(), D30Error> {
+        let arrow = match direction {
+            Direction::Sent => '>',
+            Direction::Received => '<',
+        };
+        writeln!(
+            self.out,
+            "{:.6} {} {}",
+            offset.as_secs_f64(),
+            arrow,
+            hex::encode(bytes)
+        )
+        .and_then(|_| self.out.flush())
+        .context(CouldNotWriteCaptureSnafu)
+    }
+}
+
+pub fn read_capture(reader: impl BufRead) -> Result<Vec<CaptureRecord>, D30Error> {
+    let mut lines = reader.lines();
+    // Checked so that replaying some other file fails right away, instead of on a later line
+    let header = lines.next().transpose().context(CouldNotReadCaptureSnafu)?;
+    if header.as_deref().map(str::trim) != Some(HEADER) {
+        return NotACaptureSnafu { header: HEADER }.fail();
+    }
+    let mut records = Vec::new();
+    // Line numbers count from 1, and the header was line 1
+    for (line_num, line) in (2usize..).zip(lines) {
+        let line = line.context(CouldNotReadCaptureSnafu)?;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = || InvalidCaptureRecordSnafu { line: line_num }.build();
+        let mut fields = line.split_whitespace();
+        let offset = fields
+            .next()
+            .and_then(|offset| offset.parse::<f64>().ok())
+            .filter(|offset| offset.is_finite() && *offset >= 0.0)
+            .ok_or_else(invalid)?;
+        let direction = match fields.next() {
+            Some(">") => Direction::Sent,
+            Some("<") => Direction::Received,
+            _ => return Err(invalid()),
+        };
+        let bytes = hex::decode(fields.next().unwrap_or_default()).map_err(|_| invalid())?;
+        records.push(CaptureRecord {
+            offset: Duration::from_secs_f64(offset),
+            direction,
+            bytes,
+        });
+    }
+    Ok(records)
+}
+
+/// Send the `Sent` records of a capture, byte for byte and in the same chunks.
+/// With `keep_timing`, the gaps between writes are reproduced as well.
+pub fn replay(
+    transport: &mut dyn Transport,
+    records: &[CaptureRecord],
+    keep_timing: bool,
+) -> Result<(), D30Error> {
+    let start = Instant::now();
+    for record in records
+        .iter()
+        .filter(|record| record.direction == Direction::Sent)
+    {
+        if keep_timing {
+            if let Some(wait) = record.offset.checked_sub(start.elapsed()) {
+                thread::sleep(wait);
+            }
+        }
+        debug!("Replaying {} bytes", record.bytes.len());
+        transport
+            .write_all(&record.bytes)
+            .and_then(|_| transport.flush())
+            .context(CouldNotWriteSnafu)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{
+        io::Cursor,
+        sync::{Arc, Mutex},
+    };
+
+    /// A `Write` that can still be read after the `CaptureWriter` owning it is gone
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn read(text: &str) -> Result<Vec<CaptureRecord>, D30Error> {
+        read_capture(Cursor::new(text))
+    }
+
+    #[test]
+    fn round_trip() {
+        let out = Shared::default();
+        let mut writer = CaptureWriter::new(Box::new(out.clone()), MacAddr6::nil()).unwrap();
+        writer
+            .record_at(Duration::ZERO, Direction::Sent, &[0x1f, 0x11, 0x38])
+            .unwrap();
+        writer
+            .record_at(
+                Duration::from_micros(801_233),
+                Direction::Received,
+                &[0x1a, 0x0f, 0x0c],
+            )
+            .unwrap();
+        writer
+            .record_at(Duration::from_secs(2), Direction::Sent, &[])
+            .unwrap();
+        drop(writer);
+
+        let text = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let records = read(&text).unwrap();
+        let summary: Vec<(Duration, Direction, Vec<u8>)> = records
+            .into_iter()
+            .map(|record| (record.offset, record.direction, record.bytes))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (Duration::ZERO, Direction::Sent, vec![0x1f, 0x11, 0x38]),
+                (
+                    Duration::from_micros(801_233),
+                    Direction::Received,
+                    vec![0x1a, 0x0f, 0x0c]
+                ),
+                (Duration::from_secs(2), Direction::Sent, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_reported() {
+        for (line, record) in [
+            ("0.5 > 1f1", 3),
+            ("0.5 ? 1f11", 3),
+            ("-1 > 1f11", 3),
+            ("inf > 1f11", 3),
+            ("soon > 1f11", 3),
+        ] {
+            let text = format!("{}\n# device 00:00:00:00:00:00\n{}\n", HEADER, line);
+            let result = read(&text);
+            assert!(
+                matches!(result, Err(D30Error::InvalidCaptureRecord { line }) if line == record),
+                "{:?}: {:?}",
+                line,
+                result
+            );
+        }
+    }
+
+    #[test]
+    fn header_is_required() {
+        assert!(read(&format!("{}\n\n# comment\n0.1 > 1f11\n", HEADER)).is_ok());
+        for text in ["0.1 > 1f11\n", "# some other file\n0.1 > 1f11\n", ""] {
+            assert!(
+                matches!(read(text), Err(D30Error::NotACapture { .. })),
+                "{:?}",
+                text
+            );
+        }
+    }
+}
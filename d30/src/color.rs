@@ -0,0 +1,75 @@
+//! Converting color images, transparency included, to the grayscale the rest of the pipeline works on.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{DynamicImage, GrayImage, Luma, Rgb};
+
+/// Composited pixels with every channel at least this bright are blank with `Grayscale::NonWhite`
+const WHITE_LEVEL: u8 = 250;
+
+/// How colors are turned into shades of gray
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Grayscale {
+    /// Perceived brightness, so colors print as dark as they look
+    #[default]
+    #[display(fmt = "luma")]
+    Luma,
+    #[display(fmt = "red")]
+    Red,
+    #[display(fmt = "green")]
+    Green,
+    #[display(fmt = "blue")]
+    Blue,
+    /// Everything that isn't white is black, e.g. for pale colored logos
+    #[display(fmt = "non-white")]
+    NonWhite,
+}
+
+impl FromStr for Grayscale {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "luma" => Ok(Self::Luma),
+            "red" => Ok(Self::Red),
+            "green" => Ok(Self::Green),
+            "blue" => Ok(Self::Blue),
+            "non-white" => Ok(Self::NonWhite),
+            _ => Err(format!(
+                "Invalid grayscale mode: {}. Expected `luma`, `red`, `green`, `blue` or `non-white`",
+                s
+            )),
+        }
+    }
+}
+
+/// Blend transparent pixels onto `background`, then convert to gray
+pub fn to_gray(image: &DynamicImage, background: Rgb<u8>, mode: Grayscale) -> GrayImage {
+    let image = image.to_rgba8();
+    let mut output = GrayImage::new(image.width(), image.height());
+    for (gray, pixel) in output.pixels_mut().zip(image.pixels()) {
+        let alpha = pixel[3] as u32;
+        let [r, g, b] = [0, 1, 2].map(|i| {
+            ((pixel[i] as u32 * alpha + background[i] as u32 * (255 - alpha)) / 255) as u8
+        });
+        *gray = match mode {
+            Grayscale::Luma => {
+                // Same weights as `image`'s own conversion, ITU-R BT.709
+                let luma = (2126 * r as u32 + 7152 * g as u32 + 722 * b as u32) / 10000;
+                Luma([luma as u8])
+            }
+            Grayscale::Red => Luma([r]),
+            Grayscale::Green => Luma([g]),
+            Grayscale::Blue => Luma([b]),
+            Grayscale::NonWhite => {
+                if r.min(g).min(b) >= WHITE_LEVEL {
+                    Luma([255])
+                } else {
+                    Luma([0])
+                }
+            }
+        };
+    }
+    output
+}
@@ -0,0 +1,248 @@
+use std::{
+    mem::ManuallyDrop,
+    thread,
+    time::{Duration, Instant},
+};
+
+use advmac::MacAddr6;
+use bluetooth_serial_port_async::{BtAddr, BtAsync, BtError, BtProtocol, BtSocket};
+use derive_more::Display;
+use log::{debug, info, warn};
+use rand::Rng;
+use snafu::{IntoError, ResultExt};
+
+use crate::{
+    ConnectTimedOutSnafu, CouldNotConfigureSocketSnafu, CouldNotConnectSnafu,
+    CouldNotOpenSocketSnafu, D30Error, PollSnafu, RetriesExhaustedSnafu,
+};
+
+/// How hard `connect` tries before giving up.
+///
+/// The wait before retry `n` (counting from zero) is `initial_delay * multiplier^n`,
+/// capped at `max_delay`, then randomly stretched or shrunk by up to `jitter` (a fraction).
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub multiplier: f32,
+    pub jitter: f32,
+    /// Per attempt, from the service lookup to the established connection. `None` waits for the
+    /// Bluetooth stack to give up on its own
+    pub connect_timeout: Option<Duration>,
+    /// Applied to the connected socket, so a stalled printer can't hang a print forever
+    pub write_timeout: Option<Duration>,
+    /// How long to wait for (more) response bytes before assuming the printer is done talking
+    pub read_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 10,
+            initial_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            multiplier: 2.0,
+            jitter: 0.2,
+            connect_timeout: Some(Duration::from_secs(15)),
+            write_timeout: Some(Duration::from_secs(10)),
+            read_timeout: Some(Duration::from_millis(500)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after failed attempt number `attempt` (counting from zero)
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let exponent = attempt.min(i32::MAX as usize) as i32;
+        // Long waits overflow a `Duration` long before the exponent runs out
+        let delay = Duration::try_from_secs_f32(
+            self.initial_delay.as_secs_f32() * self.multiplier.powi(exponent),
+        )
+        .unwrap_or(self.max_delay)
+        .min(self.max_delay);
+        if self.jitter <= 0.0 {
+            return delay;
+        }
+        let factor = 1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter);
+        Duration::try_from_secs_f32(delay.as_secs_f32() * factor.max(0.0)).unwrap_or(delay)
+    }
+}
+
+/// Broad reason a connection attempt failed, used to decide whether retrying can help
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectErrorKind {
+    #[display(fmt = "host is down or out of range")]
+    HostDown,
+    #[display(fmt = "device is busy")]
+    DeviceBusy,
+    #[display(fmt = "timed out")]
+    TimedOut,
+    #[display(fmt = "permission denied")]
+    PermissionDenied,
+    #[display(fmt = "no usable Bluetooth adapter")]
+    NoAdapter,
+    #[display(fmt = "unknown error")]
+    Other,
+}
+
+impl ConnectErrorKind {
+    pub fn classify(error: &BtError) -> Self {
+        let errno = match error {
+            BtError::Errno(errno, _) => *errno as i32,
+            BtError::IoError(e) => match e.raw_os_error() {
+                Some(errno) => errno,
+                None => return Self::Other,
+            },
+            BtError::Unknown | BtError::Desc(_) => return Self::Other,
+        };
+        match errno {
+            libc::EHOSTDOWN | libc::EHOSTUNREACH | libc::ECONNREFUSED | libc::ECONNRESET => {
+                Self::HostDown
+            }
+            libc::EBUSY | libc::EAGAIN | libc::EALREADY => Self::DeviceBusy,
+            libc::ETIMEDOUT => Self::TimedOut,
+            libc::EACCES | libc::EPERM => Self::PermissionDenied,
+            libc::EAFNOSUPPORT | libc::EPROTONOSUPPORT | libc::ENODEV | libc::EADDRNOTAVAIL => {
+                Self::NoAdapter
+            }
+            _ => Self::Other,
+        }
+    }
+
+    /// Whether trying again later stands a chance of working
+    pub fn is_recoverable(&self) -> bool {
+        !matches!(self, Self::PermissionDenied | Self::NoAdapter)
+    }
+}
+
+/// Connect to a printer over RFCOMM, retrying according to `policy`.
+/// Errors that retrying can't fix, like a missing adapter, are returned immediately.
+pub fn connect(addr: MacAddr6, policy: &RetryPolicy) -> Result<BtSocket, D30Error> {
+    let mut attempt = 0;
+    loop {
+        info!("Connection attempt #{}", attempt);
+        let error = match connect_once(addr, policy) {
+            Ok(socket) => return Ok(socket),
+            Err(e) => e,
+        };
+        if !error.is_recoverable() {
+            warn!("Not retrying: {}", error);
+            return Err(error);
+        }
+        if attempt >= policy.max_retries {
+            return Err(error).context(RetriesExhaustedSnafu {
+                addr,
+                attempts: attempt + 1,
+            });
+        }
+        let delay = policy.delay(attempt);
+        warn!(
+            "Connection attempt #{} failed: {}. Retrying in {:.1}s",
+            attempt,
+            error,
+            delay.as_secs_f32()
+        );
+        thread::sleep(delay);
+        attempt += 1;
+    }
+}
+
+fn connect_once(addr: MacAddr6, policy: &RetryPolicy) -> Result<BtSocket, D30Error> {
+    let mut socket = BtSocket::new(BtProtocol::RFCOMM).map_err(|source| {
+        CouldNotOpenSocketSnafu {
+            kind: ConnectErrorKind::classify(&source),
+        }
+        .into_error(source)
+    })?;
+    let deadline = policy
+        .connect_timeout
+        .map(|timeout| Instant::now() + timeout);
+    let could_not_connect = |source: BtError| {
+        CouldNotConnectSnafu {
+            addr,
+            kind: ConnectErrorKind::classify(&source),
+        }
+        .into_error(source)
+    };
+
+    // The std stream shares the socket's file descriptor, so it must never be dropped (and close it)
+    let stream = ManuallyDrop::new(socket.get_stream_std());
+    // Otherwise the RFCOMM connect() after the SDP query blocks, and the deadline can't cut it short
+    stream
+        .set_nonblocking(true)
+        .context(CouldNotConfigureSocketSnafu)?;
+
+    {
+        let poll = mio::Poll::new().context(PollSnafu)?;
+        let mut events = mio::Events::with_capacity(2);
+        let mut connect = socket.connect_async(BtAddr(addr.to_array()));
+        let in_progress = loop {
+            match connect.advance() {
+                Ok(BtAsync::WaitFor(evented, interest)) => wait_for(
+                    &poll,
+                    &mut events,
+                    evented,
+                    interest,
+                    addr,
+                    policy,
+                    deadline,
+                )?,
+                Ok(BtAsync::Done) => break false,
+                // The library takes this for a failure, but the connection is just under way
+                Err(BtError::Errno(errno, _)) if errno as i32 == libc::EINPROGRESS => break true,
+                Err(source) => return Err(could_not_connect(source)),
+            }
+        };
+        drop(connect);
+        if in_progress {
+            wait_for(
+                &poll,
+                &mut events,
+                &socket,
+                mio::Ready::writable(),
+                addr,
+                policy,
+                deadline,
+            )?;
+            if let Some(source) = stream.take_error().context(PollSnafu)? {
+                return Err(could_not_connect(BtError::IoError(source)));
+            }
+        }
+    }
+    debug!("Connected to {}", addr);
+
+    stream
+        .set_nonblocking(false)
+        .and_then(|_| stream.set_write_timeout(policy.write_timeout))
+        .and_then(|_| stream.set_read_timeout(policy.read_timeout))
+        .context(CouldNotConfigureSocketSnafu)?;
+    Ok(socket)
+}
+
+/// Wait until `evented` is ready for `interest`, or fail once `deadline` has passed
+fn wait_for(
+    poll: &mio::Poll,
+    events: &mut mio::Events,
+    evented: &dyn mio::Evented,
+    interest: mio::Ready,
+    addr: MacAddr6,
+    policy: &RetryPolicy,
+    deadline: Option<Instant>,
+) -> Result<(), D30Error> {
+    poll.register(evented, mio::Token(0), interest, mio::PollOpt::oneshot())
+        .context(PollSnafu)?;
+    loop {
+        let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        poll.poll(events, remaining).context(PollSnafu)?;
+        if !events.is_empty() {
+            break;
+        }
+        if let (Some(timeout), Some(deadline)) = (policy.connect_timeout, deadline) {
+            if Instant::now() >= deadline {
+                return ConnectTimedOutSnafu { addr, timeout }.fail();
+            }
+        }
+    }
+    poll.deregister(evented).context(PollSnafu)
+}
@@ -0,0 +1,347 @@
+//! Reducing grayscale labels to the printer's 1-bit output.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{DynamicImage, GrayImage, Luma, Rgb};
+
+use crate::{to_gray, Grayscale, COLOR_BLACK, PACK_THRESHOLD};
+
+/// How far above the local average a pixel's ink level has to be with `Threshold::Adaptive`.
+/// Keeps flat areas from turning into noise
+const ADAPTIVE_OFFSET: f32 = 8.0;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Dither {
+    /// Every pixel on its own, at the same threshold `pack_image` uses
+    #[default]
+    #[display(fmt = "threshold")]
+    Threshold,
+    #[display(fmt = "floyd-steinberg")]
+    FloydSteinberg,
+    /// Only diffuses 3/4 of the error, which keeps more contrast
+    #[display(fmt = "atkinson")]
+    Atkinson,
+    #[display(fmt = "stucki")]
+    Stucki,
+    /// Ordered dithering, with a regular pattern instead of noise
+    #[display(fmt = "bayer4")]
+    Bayer4,
+    #[display(fmt = "bayer8")]
+    Bayer8,
+}
+
+impl FromStr for Dither {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "threshold" | "none" => Ok(Self::Threshold),
+            "floyd-steinberg" | "fs" => Ok(Self::FloydSteinberg),
+            "atkinson" => Ok(Self::Atkinson),
+            "stucki" => Ok(Self::Stucki),
+            "bayer4" => Ok(Self::Bayer4),
+            "bayer8" => Ok(Self::Bayer8),
+            _ => Err(format!(
+                "Invalid dither: {}. Expected `threshold`, `floyd-steinberg`, `atkinson`, `stucki`, `bayer4` or `bayer8`",
+                s
+            )),
+        }
+    }
+}
+
+/// Where `Dither::Threshold` draws the line between ink and blank
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Threshold {
+    /// Pixels with an ink level (0 to 255, inverse of brightness) above this are printed
+    #[display(fmt = "{}", _0)]
+    Value(u8),
+    /// Picked from the image's histogram with Otsu's method. Good for scans and photographed documents
+    #[display(fmt = "otsu")]
+    Otsu,
+    /// Compare every pixel to the average of the block `radius` pixels around it.
+    /// Copes with uneven lighting
+    #[display(fmt = "adaptive:{}", radius)]
+    Adaptive { radius: u32 },
+}
+
+impl Default for Threshold {
+    fn default() -> Self {
+        Self::Value(PACK_THRESHOLD)
+    }
+}
+
+/// Accepts a number from 0 to 255, `otsu`, `adaptive`, or `adaptive:<radius>` in pixels
+impl FromStr for Threshold {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid threshold: {}. Expected 0-255, `otsu`, `adaptive` or `adaptive:<radius>`",
+                s
+            )
+        };
+        match s {
+            "otsu" => Ok(Self::Otsu),
+            "adaptive" => Ok(Self::Adaptive { radius: 8 }),
+            _ => match s.strip_prefix("adaptive:") {
+                Some(radius) => match radius.parse::<u32>() {
+                    Ok(radius) if radius > 0 => Ok(Self::Adaptive { radius }),
+                    _ => Err(invalid()),
+                },
+                None => s.parse::<u8>().map(Self::Value).map_err(|_| invalid()),
+            },
+        }
+    }
+}
+
+fn threshold(ink: &GrayImage, threshold: Threshold) -> GrayImage {
+    let level = match threshold {
+        Threshold::Value(level) => level,
+        Threshold::Otsu => imageproc::contrast::otsu_level(ink),
+        Threshold::Adaptive { radius } => return adaptive(ink, radius),
+    };
+    let mut output = ink.clone();
+    for pixel in output.pixels_mut() {
+        pixel[0] = if pixel[0] > level { 255 } else { 0 };
+    }
+    output
+}
+
+fn adaptive(ink: &GrayImage, radius: u32) -> GrayImage {
+    let (width, height) = ink.dimensions();
+    // Summed area table, with an extra row and column of zeros in front
+    let stride = width as usize + 1;
+    let mut sums = vec![0u64; stride * (height as usize + 1)];
+    for y in 0..height as usize {
+        for x in 0..width as usize {
+            sums[(y + 1) * stride + x + 1] = ink.get_pixel(x as u32, y as u32)[0] as u64
+                + sums[y * stride + x + 1]
+                + sums[(y + 1) * stride + x]
+                - sums[y * stride + x];
+        }
+    }
+    let mut output = GrayImage::new(width, height);
+    for (x, y, pixel) in output.enumerate_pixels_mut() {
+        let (left, top) = (
+            x.saturating_sub(radius) as usize,
+            y.saturating_sub(radius) as usize,
+        );
+        let right = (x + radius + 1).min(width) as usize;
+        let bottom = (y + radius + 1).min(height) as usize;
+        let sum = sums[bottom * stride + right] + sums[top * stride + left]
+            - sums[top * stride + right]
+            - sums[bottom * stride + left];
+        let mean = sum as f32 / ((right - left) * (bottom - top)) as f32;
+        let value = ink.get_pixel(x, y)[0] as f32;
+        pixel[0] = if value > mean + ADAPTIVE_OFFSET && value > 0.0 {
+            255
+        } else {
+            0
+        };
+    }
+    output
+}
+
+/// Error diffusion kernels as `(dx, dy, weight)`, and the sum the weights are divided by
+const FLOYD_STEINBERG: (&[(i32, i32, f32)], f32) =
+    (&[(1, 0, 7.0), (-1, 1, 3.0), (0, 1, 5.0), (1, 1, 1.0)], 16.0);
+const ATKINSON: (&[(i32, i32, f32)], f32) = (
+    &[
+        (1, 0, 1.0),
+        (2, 0, 1.0),
+        (-1, 1, 1.0),
+        (0, 1, 1.0),
+        (1, 1, 1.0),
+        (0, 2, 1.0),
+    ],
+    8.0,
+);
+const STUCKI: (&[(i32, i32, f32)], f32) = (
+    &[
+        (1, 0, 8.0),
+        (2, 0, 4.0),
+        (-2, 1, 2.0),
+        (-1, 1, 4.0),
+        (0, 1, 8.0),
+        (1, 1, 4.0),
+        (2, 1, 2.0),
+        (-2, 2, 1.0),
+        (-1, 2, 2.0),
+        (0, 2, 4.0),
+        (1, 2, 2.0),
+        (2, 2, 1.0),
+    ],
+    42.0,
+);
+
+const BAYER4: [[u8; 4]; 4] = [[0, 8, 2, 10], [12, 4, 14, 6], [3, 11, 1, 9], [15, 7, 13, 5]];
+
+/// Bayer matrix entry for an `n` by `n` matrix, with `n` a power of two
+fn bayer(n: u32, x: u32, y: u32) -> u32 {
+    if n == 4 {
+        return BAYER4[(y % 4) as usize][(x % 4) as usize] as u32;
+    }
+    let half = n / 2;
+    let quadrant = match ((x % n) / half, (y % n) / half) {
+        (0, 0) => 0,
+        (1, 1) => 1,
+        (1, 0) => 2,
+        _ => 3,
+    };
+    4 * bayer(half, x, y) + quadrant
+}
+
+fn diffuse(ink: &GrayImage, (kernel, divisor): (&[(i32, i32, f32)], f32)) -> GrayImage {
+    let (width, height) = ink.dimensions();
+    let mut values: Vec<f32> = ink.pixels().map(|pixel| pixel[0] as f32).collect();
+    let mut output = GrayImage::new(width, height);
+    for y in 0..height {
+        for x in 0..width {
+            let value = values[(y * width + x) as usize];
+            let printed = if value > PACK_THRESHOLD as f32 {
+                255.0
+            } else {
+                0.0
+            };
+            output.put_pixel(x, y, Luma([printed as u8]));
+            let error = value - printed;
+            for (dx, dy, weight) in kernel {
+                let (nx, ny) = (x as i32 + dx, y as i32 + dy);
+                if nx >= 0 && nx < width as i32 && ny < height as i32 {
+                    values[(ny as u32 * width + nx as u32) as usize] += error * weight / divisor;
+                }
+            }
+        }
+    }
+    output
+}
+
+fn ordered(ink: &GrayImage, n: u32) -> GrayImage {
+    let mut output = ink.clone();
+    for (x, y, pixel) in output.enumerate_pixels_mut() {
+        let threshold = (bayer(n, x, y) as f32 + 0.5) / (n * n) as f32 * 255.0;
+        pixel[0] = if pixel[0] as f32 > threshold { 255 } else { 0 };
+    }
+    output
+}
+
+/// Reduce a label from `generate_image` or `fit_image` to pure ink and blank pixels.
+/// `threshold` only applies to `Dither::Threshold`.
+/// `pack_image` sends the result exactly as it is, so it doubles as an accurate preview.
+pub fn binarize(image: &DynamicImage, dither: Dither, threshold: Threshold) -> DynamicImage {
+    // Transparent pixels are blank
+    let ink = to_gray(image, Rgb([0, 0, 0]), Grayscale::Luma);
+    let ink = match dither {
+        Dither::Threshold => self::threshold(&ink, threshold),
+        Dither::FloydSteinberg => diffuse(&ink, FLOYD_STEINBERG),
+        Dither::Atkinson => diffuse(&ink, ATKINSON),
+        Dither::Stucki => diffuse(&ink, STUCKI),
+        Dither::Bayer4 => ordered(&ink, 4),
+        Dither::Bayer8 => ordered(&ink, 8),
+    };
+    let mut output = image::RgbImage::new(ink.width(), ink.height());
+    for (pixel, ink) in output.pixels_mut().zip(ink.pixels()) {
+        if ink[0] > PACK_THRESHOLD {
+            *pixel = COLOR_BLACK;
+        } else {
+            *pixel = Rgb([0, 0, 0]);
+        }
+    }
+    DynamicImage::from(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SIZE: u32 = 64;
+
+    fn flat(level: u8) -> GrayImage {
+        GrayImage::from_pixel(SIZE, SIZE, Luma([level]))
+    }
+
+    /// Fraction of pixels that get ink
+    fn coverage(image: &GrayImage) -> f32 {
+        image.pixels().filter(|pixel| pixel[0] == 255).count() as f32 / (SIZE * SIZE) as f32
+    }
+
+    #[test]
+    fn bayer_matrices_are_permutations() {
+        for n in [4, 8] {
+            let mut entries: Vec<u32> = (0..n * n).map(|i| bayer(n, i % n, i / n)).collect();
+            entries.sort_unstable();
+            assert_eq!(entries, (0..n * n).collect::<Vec<_>>(), "{}x{}", n, n);
+        }
+        // The pattern tiles
+        assert_eq!(bayer(8, 3, 5), bayer(8, 11, 13));
+    }
+
+    #[test]
+    fn ordered_dithering_matches_gray_levels() {
+        for (level, expected) in [(32, 0.125), (64, 0.25), (128, 0.5), (192, 0.75)] {
+            assert_eq!(coverage(&ordered(&flat(level), 4)), expected);
+            assert_eq!(coverage(&ordered(&flat(level), 8)), expected);
+        }
+    }
+
+    #[test]
+    fn error_diffusion_matches_gray_levels() {
+        for level in [32, 64, 128, 192, 224] {
+            let expected = level as f32 / 255.0;
+            for (name, kernel) in [("floyd-steinberg", FLOYD_STEINBERG), ("stucki", STUCKI)] {
+                let actual = coverage(&diffuse(&flat(level), kernel));
+                // Error that falls off the edges is lost
+                assert!(
+                    (actual - expected).abs() < 0.02,
+                    "{} at {}: {}",
+                    name,
+                    level,
+                    actual
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn atkinson_keeps_contrast() {
+        assert!((coverage(&diffuse(&flat(128), ATKINSON)) - 0.5).abs() < 0.02);
+        // Only part of the error is passed on, so light grays get lighter and dark ones darker
+        assert!(coverage(&diffuse(&flat(64), ATKINSON)) < 0.25);
+        assert!(coverage(&diffuse(&flat(192), ATKINSON)) > 0.75);
+        assert_eq!(coverage(&diffuse(&flat(0), ATKINSON)), 0.0);
+        assert_eq!(coverage(&diffuse(&flat(255), ATKINSON)), 1.0);
+    }
+
+    #[test]
+    fn adaptive_threshold_leaves_flat_areas_blank() {
+        for level in [0, 64, 128, 192, 255] {
+            assert_eq!(coverage(&adaptive(&flat(level), 8)), 0.0, "{}", level);
+        }
+    }
+
+    #[test]
+    fn adaptive_threshold_finds_marks_on_any_background() {
+        let is_mark = |x: u32, y: u32| x % 16 == 8 && y % 16 == 8;
+        for background in [40, 200] {
+            let mut ink = flat(background);
+            for (x, y, pixel) in ink.enumerate_pixels_mut() {
+                if is_mark(x, y) {
+                    pixel[0] = background + 50;
+                }
+            }
+            let output = threshold(&ink, Threshold::Adaptive { radius: 4 });
+            for (x, y, pixel) in output.enumerate_pixels() {
+                assert_eq!(
+                    pixel[0] == 255,
+                    is_mark(x, y),
+                    "{} at {},{}",
+                    background,
+                    x,
+                    y
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,300 @@
+//! Mapping arbitrary images onto the label canvas.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{imageops::FilterType, DynamicImage, GenericImageView, ImageBuffer, Rgb};
+
+use crate::{to_gray, Adjustments, Canvas, Grayscale, Margins};
+
+/// Pixels at least this bright count as blank border for `FitOptions::trim`
+const TRIM_LEVEL: u8 = 250;
+
+/// How the image is scaled into the printable area
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FitMode {
+    /// As large as possible while showing the whole image
+    #[default]
+    #[display(fmt = "contain")]
+    Contain,
+    /// Fill the whole area, cropping what sticks out
+    #[display(fmt = "cover")]
+    Cover,
+    /// Fill the whole area, ignoring the aspect ratio
+    #[display(fmt = "stretch")]
+    Stretch,
+    /// One image pixel per dot, cropped if it doesn't fit
+    #[display(fmt = "center")]
+    Center,
+}
+
+impl FromStr for FitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "contain" => Ok(Self::Contain),
+            "cover" => Ok(Self::Cover),
+            "stretch" => Ok(Self::Stretch),
+            "center" => Ok(Self::Center),
+            _ => Err(format!(
+                "Invalid fit mode: {}. Expected `contain`, `cover`, `stretch` or `center`",
+                s
+            )),
+        }
+    }
+}
+
+/// Resampling filter used when scaling
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Resample {
+    /// Keeps hard edges, for pixel art and QR codes
+    #[display(fmt = "nearest")]
+    Nearest,
+    #[default]
+    #[display(fmt = "triangle")]
+    Triangle,
+    #[display(fmt = "catmull-rom")]
+    CatmullRom,
+    #[display(fmt = "gaussian")]
+    Gaussian,
+    /// Sharpest, for photos
+    #[display(fmt = "lanczos")]
+    Lanczos,
+}
+
+impl FromStr for Resample {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nearest" => Ok(Self::Nearest),
+            "triangle" | "linear" => Ok(Self::Triangle),
+            "catmull-rom" | "cubic" => Ok(Self::CatmullRom),
+            "gaussian" => Ok(Self::Gaussian),
+            "lanczos" | "lanczos3" => Ok(Self::Lanczos),
+            _ => Err(format!(
+                "Invalid resample filter: {}. Expected `nearest`, `triangle`, `catmull-rom`, `gaussian` or `lanczos`",
+                s
+            )),
+        }
+    }
+}
+
+impl From<Resample> for FilterType {
+    fn from(resample: Resample) -> Self {
+        match resample {
+            Resample::Nearest => FilterType::Nearest,
+            Resample::Triangle => FilterType::Triangle,
+            Resample::CatmullRom => FilterType::CatmullRom,
+            Resample::Gaussian => FilterType::Gaussian,
+            Resample::Lanczos => FilterType::Lanczos3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FitOptions {
+    pub mode: FitMode,
+    pub resample: Resample,
+    /// Crop white and transparent borders off the image first
+    pub trim: bool,
+    /// Turn the image by 90° if its long side would otherwise run across the label
+    pub auto_rotate: bool,
+    /// Transparent parts of the image are blended onto this color
+    pub background: Rgb<u8>,
+    pub grayscale: Grayscale,
+    /// Applied once the image is scaled, so filters work at the printer's resolution
+    pub adjustments: Adjustments,
+}
+
+impl Default for FitOptions {
+    fn default() -> Self {
+        Self {
+            mode: FitMode::default(),
+            resample: Resample::default(),
+            trim: false,
+            auto_rotate: false,
+            background: Rgb([255, 255, 255]),
+            grayscale: Grayscale::default(),
+            adjustments: Adjustments::default(),
+        }
+    }
+}
+
+fn is_blank(image: &DynamicImage, x: u32, y: u32) -> bool {
+    let pixel = image.get_pixel(x, y);
+    pixel[3] == 0 || pixel.0[..3].iter().all(|channel| *channel >= TRIM_LEVEL)
+}
+
+/// The image without rows and columns that are entirely blank, or unchanged if it's blank altogether
+fn trim(image: &DynamicImage) -> DynamicImage {
+    let (width, height) = image.dimensions();
+    let column_blank = |x: u32| (0..height).all(|y| is_blank(image, x, y));
+    let row_blank = |y: u32| (0..width).all(|x| is_blank(image, x, y));
+    let Some(left) = (0..width).find(|x| !column_blank(*x)) else {
+        return image.clone();
+    };
+    let right = (0..width).rev().find(|x| !column_blank(*x)).unwrap_or(left);
+    let top = (0..height).find(|y| !row_blank(*y)).unwrap_or(0);
+    let bottom = (0..height).rev().find(|y| !row_blank(*y)).unwrap_or(top);
+    image.crop_imm(left, top, right - left + 1, bottom - top + 1)
+}
+
+/// Whether `auto_rotate` turns a `width` by `height` image on this canvas
+pub(crate) fn should_rotate(width: u32, height: u32, canvas: Canvas, options: &FitOptions) -> bool {
+    // Without a fixed length, the long side should run along the tape
+    let area_landscape =
+        canvas.auto_length || canvas.printable.width() >= canvas.printable.height();
+    options.auto_rotate && (width >= height) != area_landscape
+}
+
+/// Like `fit_image_sized`, with control over how the image is mapped onto the canvas
+pub fn fit_image_with(image: &DynamicImage, canvas: Canvas, options: &FitOptions) -> DynamicImage {
+    let image = if options.trim {
+        trim(image)
+    } else {
+        image.clone()
+    };
+    let image = if should_rotate(image.width(), image.height(), canvas, options) {
+        image.rotate90()
+    } else {
+        image
+    };
+
+    let canvas = if canvas.auto_length {
+        let height = canvas.printable.height() as u64;
+        let length = match options.mode {
+            FitMode::Center => image.width() as u64,
+            _ => image.width() as u64 * height / (image.height() as u64).max(1),
+        };
+        canvas.fit_length(&Margins::default(), length.clamp(1, u32::MAX as u64) as u32)
+    } else {
+        canvas
+    };
+    let area = canvas.printable;
+    let source = DynamicImage::ImageLuma8(to_gray(&image, options.background, options.grayscale));
+    let filter = options.resample.into();
+    let source = match options.mode {
+        FitMode::Contain => source.resize(area.width(), area.height(), filter),
+        FitMode::Cover => source.resize_to_fill(area.width(), area.height(), filter),
+        FitMode::Stretch => source.resize_exact(area.width(), area.height(), filter),
+        FitMode::Center => {
+            let width = source.width().min(area.width());
+            let height = source.height().min(area.height());
+            source.crop_imm(
+                (source.width() - width) / 2,
+                (source.height() - height) / 2,
+                width,
+                height,
+            )
+        }
+    };
+
+    let mut source = DynamicImage::ImageLuma8(options.adjustments.apply(&source.to_luma8()));
+    source.invert();
+
+    let mut output: ImageBuffer<Rgb<u8>, _> = ImageBuffer::new(canvas.width, canvas.height);
+    image::imageops::overlay(
+        &mut output,
+        &source.to_rgb8(),
+        area.left() as i64 + ((area.width() - source.width()) / 2) as i64,
+        area.top() as i64 + ((area.height() - source.height()) / 2) as i64,
+    );
+
+    DynamicImage::from(output).rotate270()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgba, RgbaImage};
+
+    fn canvas() -> Canvas {
+        Canvas::new(320, 96, 203)
+    }
+
+    /// A black image, `width` by `height`
+    fn solid(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgba8(RgbaImage::from_pixel(width, height, Rgba([0, 0, 0, 255])))
+    }
+
+    /// Width and height of the inked part of a fitted label, in reading orientation
+    fn ink_size(label: &DynamicImage) -> (u32, u32) {
+        let label = label.rotate90().to_luma8();
+        let ink: Vec<(u32, u32)> = label
+            .enumerate_pixels()
+            .filter(|(_, _, pixel)| pixel[0] > 0)
+            .map(|(x, y, _)| (x, y))
+            .collect();
+        let extent = |coordinates: Vec<u32>| {
+            coordinates.iter().max().unwrap() - coordinates.iter().min().unwrap() + 1
+        };
+        (
+            extent(ink.iter().map(|(x, _)| *x).collect()),
+            extent(ink.iter().map(|(_, y)| *y).collect()),
+        )
+    }
+
+    fn fit(image: &DynamicImage, canvas: Canvas, options: FitOptions) -> DynamicImage {
+        fit_image_with(image, canvas, &options)
+    }
+
+    #[test]
+    fn contain_shows_the_whole_image() {
+        let label = fit(&solid(100, 50), canvas(), FitOptions::default());
+        assert_eq!(label.dimensions(), (96, 320));
+        assert_eq!(ink_size(&label), (192, 96));
+    }
+
+    #[test]
+    fn cover_fills_the_label() {
+        let options = FitOptions {
+            mode: FitMode::Cover,
+            ..FitOptions::default()
+        };
+        let label = fit(&solid(100, 50), canvas(), options);
+        assert_eq!(label.dimensions(), (96, 320));
+        assert_eq!(ink_size(&label), (320, 96));
+    }
+
+    #[test]
+    fn auto_length_follows_the_aspect_ratio() {
+        let mut canvas = canvas();
+        canvas.auto_length = true;
+        let label = fit(&solid(100, 50), canvas, FitOptions::default());
+        assert_eq!(label.height(), 192);
+    }
+
+    #[test]
+    fn trim_removes_white_border() {
+        let mut image = RgbaImage::from_pixel(60, 30, Rgba([255, 255, 255, 255]));
+        for (x, y) in [(10, 5), (29, 14)] {
+            image.put_pixel(x, y, Rgba([0, 0, 0, 255]));
+        }
+        // Transparent counts as border too, whatever its color
+        image.put_pixel(50, 25, Rgba([0, 0, 0, 0]));
+        let trimmed = trim(&DynamicImage::ImageRgba8(image));
+        assert_eq!(trimmed.dimensions(), (20, 10));
+
+        let blank =
+            DynamicImage::ImageRgba8(RgbaImage::from_pixel(8, 8, Rgba([255, 255, 255, 255])));
+        assert_eq!(trim(&blank).dimensions(), (8, 8));
+    }
+
+    #[test]
+    fn auto_rotate_turns_tall_images() {
+        let options = FitOptions {
+            auto_rotate: true,
+            ..FitOptions::default()
+        };
+        assert!(should_rotate(50, 100, canvas(), &options));
+        assert!(!should_rotate(100, 50, canvas(), &options));
+        assert!(!should_rotate(50, 100, canvas(), &FitOptions::default()));
+
+        let label = fit(&solid(50, 100), canvas(), options);
+        assert_eq!(ink_size(&label), (192, 96));
+        let label = fit(&solid(50, 100), canvas(), FitOptions::default());
+        assert_eq!(ink_size(&label), (48, 96));
+    }
+}
@@ -0,0 +1,142 @@
+//! Cable flag ("fruit") labels.
+//!
+//! The label is split along its length into three zones: a flag half, the wrap
+//! zone that goes around the cable, and another flag half. Once wrapped, the two
+//! halves stick together back to back, so the text is printed on both of them.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{imageops, DynamicImage, RgbImage};
+use snafu::ensure;
+
+use crate::{
+    render_text, Canvas, D30Error, D30Scale, Margins, TextLayout, WrapTooLongSnafu, COLOR_BLACK,
+};
+
+/// How the text on the second flag half is oriented
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlagText {
+    /// Same as on the first half
+    #[default]
+    #[display(fmt = "duplicate")]
+    Duplicate,
+    /// Turned upside down, so it reads the right way up from the other side once the flag is folded
+    #[display(fmt = "rotate")]
+    Rotate,
+}
+
+impl FromStr for FlagText {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "duplicate" => Ok(Self::Duplicate),
+            "rotate" => Ok(Self::Rotate),
+            _ => Err(format!(
+                "Invalid flag text mode: {}. Expected `duplicate` or `rotate`",
+                s
+            )),
+        }
+    }
+}
+
+/// What goes on the part of the label that wraps around the cable
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WrapFill {
+    #[default]
+    #[display(fmt = "blank")]
+    Blank,
+    /// Diagonal lines
+    #[display(fmt = "hatch")]
+    Hatch,
+    #[display(fmt = "dots")]
+    Dots,
+}
+
+impl FromStr for WrapFill {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blank" => Ok(Self::Blank),
+            "hatch" => Ok(Self::Hatch),
+            "dots" => Ok(Self::Dots),
+            _ => Err(format!(
+                "Invalid wrap fill: {}. Expected `blank`, `hatch` or `dots`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlagLayout {
+    /// Length of the wrap zone in dots, usually the cable's circumference
+    pub wrap_length: u32,
+    pub text: FlagText,
+    pub wrap_fill: WrapFill,
+}
+
+impl FlagLayout {
+    fn fill(&self, image: &mut RgbImage, start: u32) {
+        for x in start..(start + self.wrap_length).min(image.width()) {
+            for y in 0..image.height() {
+                let ink = match self.wrap_fill {
+                    WrapFill::Blank => false,
+                    WrapFill::Hatch => (x + y) % 8 == 0,
+                    WrapFill::Dots => x % 6 == 0 && y % 6 == 0,
+                };
+                if ink {
+                    image.put_pixel(x, y, COLOR_BLACK);
+                }
+            }
+        }
+    }
+}
+
+/// Render text as a cable flag. Each flag half gets the text sized as if it were a label of its own.
+/// On auto length canvases, the halves are as long as the text.
+pub fn generate_flag_image(
+    text: &str,
+    margins: &Margins,
+    font_scale: D30Scale,
+    canvas: Canvas,
+    layout: &FlagLayout,
+    text_layout: &TextLayout,
+) -> Result<DynamicImage, D30Error> {
+    let half_canvas = if canvas.auto_length {
+        canvas
+    } else {
+        ensure!(
+            canvas.width > layout.wrap_length,
+            WrapTooLongSnafu {
+                wrap: layout.wrap_length,
+                length: canvas.width,
+            }
+        );
+        canvas.resized((canvas.width - layout.wrap_length) / 2)
+    };
+    let half = render_text(text, margins, font_scale, half_canvas, text_layout)?;
+    let other_half = match layout.text {
+        FlagText::Duplicate => half.clone(),
+        FlagText::Rotate => imageops::rotate180(&half),
+    };
+
+    let width = if canvas.auto_length {
+        half.width() * 2 + layout.wrap_length
+    } else {
+        canvas.width
+    };
+    let mut output = RgbImage::new(width, canvas.height);
+    imageops::overlay(&mut output, &half, 0, 0);
+    layout.fill(&mut output, half.width());
+    imageops::overlay(
+        &mut output,
+        &other_half,
+        (width - other_half.width()) as i64,
+        0,
+    );
+
+    Ok(DynamicImage::from(output).rotate270())
+}
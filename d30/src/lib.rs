@@ -1,16 +1,56 @@
+mod adjust;
+mod barcode;
+mod calibrate;
+pub mod capture;
+mod color;
+mod connection;
+mod dither;
+mod fit;
+mod flag;
+mod media;
+mod model;
+mod orientation;
+mod printer;
+pub mod protocol;
+mod svg;
+pub mod template;
+mod text;
+mod units;
+
 use std::io;
-use std::{fs, path::PathBuf, str::FromStr};
+use std::time::Duration;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
 
 use advmac::MacAddr6;
-use image::{DynamicImage, ImageBuffer, Rgb};
+use bluetooth_serial_port_async::BtError;
+use image::{DynamicImage, ImageBuffer, Rgb, RgbImage};
 use log::{trace, warn};
 use rusttype::{Font, Scale};
 
-use dimensions::*;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, Snafu};
 
+pub use adjust::{Adjustments, Effect};
+pub use calibrate::generate_calibration_image;
+pub use color::{to_gray, Grayscale};
+pub use connection::{connect, ConnectErrorKind, RetryPolicy};
+pub use dither::{binarize, Dither, Threshold};
+pub use fit::{fit_image_with, FitMode, FitOptions, Resample};
+pub use flag::{generate_flag_image, FlagLayout, FlagText, WrapFill};
+pub use media::{Canvas, Media, MediaKind, PROFILES};
+pub use model::{Model, ModelSpec, Support, TransportKind};
+pub use orientation::{Mirror, Orientation, Rotation};
+pub use printer::{D30Printer, PrinterState, Transport};
+pub use svg::rasterize_svg;
+pub(crate) use text::{fit_scale, render_text};
+pub use text::{Align, TextLayout, VerticalAlign};
+pub use units::{Length, Margins, Unit};
+
 // These values are based on those used in polskafan's phomemo_d30 code, available here:
 // https://github.com/polskafan/phomemo_d30
 pub const INIT_BASE_FLAT: &[u8] = &[
@@ -25,12 +65,19 @@ pub const INIT_BASE_FLAT: &[u8] = &[
 
 pub const IMG_PRECURSOR: &[u8] = &[31, 17, 36, 0, 27, 64, 29, 118, 48, 0, 12, 0, 64, 1]; // 1f1124001b401d7630000c004001
 
-const COLOR_BLACK: image::Rgb<u8> = Rgb([255u8, 255u8, 255u8]);
+pub(crate) const COLOR_BLACK: image::Rgb<u8> = Rgb([255u8, 255u8, 255u8]);
+/// Pixels brighter than this are ink
+pub(crate) const PACK_THRESHOLD: u8 = 127;
 
+/// Font size. Bare numbers are in pixels
 #[derive(Debug, Clone, Copy)]
 pub enum D30Scale {
-    Value(f32),
-    Auto { minus: f32 },
+    Value(Length),
+    /// The largest size that fits, as long as it is between `min` and `max`
+    Auto {
+        min: Option<Length>,
+        max: Option<Length>,
+    },
 }
 
 impl FromStr for D30Scale {
@@ -38,77 +85,151 @@ impl FromStr for D30Scale {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "auto" => Ok(Self::Auto { minus: 0.0 }),
-            _ => s
-                .parse::<f32>()
-                .map(Self::Value)
-                .map_err(|_| format!("Invalid value: {}", s)),
+            "auto" => Ok(Self::Auto {
+                min: None,
+                max: None,
+            }),
+            _ => s.parse::<Length>().map(Self::Value),
         }
     }
 }
 
+/// Canvas of the D30's stock 12x40mm labels
+fn default_canvas() -> Canvas {
+    let spec = Model::D30.spec();
+    spec.canvas(&spec.default_media())
+}
+
+/// Render text onto a D30 12x40mm label
 pub fn generate_image(
     text: &str,
-    margins: f32,
+    margins: &Margins,
     font_scale: D30Scale,
 ) -> Result<DynamicImage, D30Error> {
-    let label_dimensions = Dimensions::new(320, 96);
-    trace!("{:#?}", &label_dimensions);
+    generate_image_sized(text, margins, font_scale, default_canvas())
+}
+
+/// Render text onto a label canvas from `ModelSpec::canvas`, centered in its printable area.
+/// On auto length canvases, the text is sized to the tape width and the label is as long as the text.
+pub fn generate_image_sized(
+    text: &str,
+    margins: &Margins,
+    font_scale: D30Scale,
+    canvas: Canvas,
+) -> Result<DynamicImage, D30Error> {
+    generate_text_image(text, margins, font_scale, canvas, &TextLayout::default())
+}
+
+/// Like `generate_image_sized`, for text with several lines. Lines are split at newlines,
+/// and between words too if `layout` wraps them. Auto scaling fits the whole block.
+pub fn generate_text_image(
+    text: &str,
+    margins: &Margins,
+    font_scale: D30Scale,
+    canvas: Canvas,
+    layout: &TextLayout,
+) -> Result<DynamicImage, D30Error> {
+    let canvas = render_text(text, margins, font_scale, canvas, layout)?;
+    Ok(DynamicImage::from(canvas).rotate270())
+}
+
+/// Render text in portrait layout: the characters are stacked upright, one below the other,
+/// and the stack runs along the label from its start. Auto length canvases are treated as fixed
+pub fn generate_portrait_image(
+    text: &str,
+    margins: &Margins,
+    font_scale: D30Scale,
+    canvas: Canvas,
+) -> Result<DynamicImage, D30Error> {
+    let canvas = canvas.transposed();
+    let area = canvas.content_area(margins);
     let font = Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]);
     let font = Font::try_from_vec(font).context(CouldNotInitFontSnafu)?;
-    // let scale = Scale::uniform(font_scale);
-
-    let scale = match font_scale {
-        D30Scale::Auto { minus } => {
-            // let scale = 100.0;
-            let actual_size: Dimensions =
-                imageproc::drawing::text_size(Scale::uniform(100.0), &font, &text).into();
-            let scale_by_x = (label_dimensions.x - 2.0 * margins) / actual_size.x;
-            let scale_by_y = (label_dimensions.y - 2.0 * margins) / actual_size.y;
-            100.0
-                * if scale_by_y > scale_by_x {
-                    scale_by_x
-                } else {
-                    scale_by_y
-                }
-                - minus
-        }
-        D30Scale::Value(font_scale) => font_scale,
+    let characters: Vec<String> = text
+        .chars()
+        .filter(|c| *c != '\n')
+        .map(String::from)
+        .collect();
+    let line_height = |scale: f32| {
+        let metrics = font.v_metrics(Scale::uniform(scale));
+        metrics.ascent - metrics.descent
     };
-    let actual_size: Dimensions =
-        imageproc::drawing::text_size(Scale::uniform(scale), &font, &text).into();
-    let txt_pos = (actual_size - label_dimensions) / -2.;
 
-    let mut canvas: ImageBuffer<Rgb<u8>, _> = ImageBuffer::new(
-        label_dimensions.width() as u32,
-        label_dimensions.height() as u32,
-    );
+    let scale = fit_scale(font_scale, canvas.dpi, area.height() as f32, |scale| {
+        let widest = characters
+            .iter()
+            .map(|c| imageproc::drawing::text_size(Scale::uniform(scale), &font, c).0)
+            .max()
+            .unwrap_or(0);
+        widest <= area.width() as i32
+            && line_height(scale) * characters.len() as f32 <= area.height() as f32
+    });
 
-    imageproc::drawing::draw_text_mut(
-        &mut canvas,
-        COLOR_BLACK,
-        txt_pos.x as i32,
-        txt_pos.y as i32,
-        Scale::uniform(scale),
-        &font,
-        text,
-    );
+    let height = line_height(scale);
+    let top = area.top() as f32 + (area.height() as f32 - height * characters.len() as f32) / 2.0;
+    let mut output = RgbImage::new(canvas.width, canvas.height);
+    for (i, character) in characters.iter().enumerate() {
+        let (width, _) = imageproc::drawing::text_size(Scale::uniform(scale), &font, character);
+        imageproc::drawing::draw_text_mut(
+            &mut output,
+            COLOR_BLACK,
+            area.left() + (area.width() as i32 - width) / 2,
+            (top + height * i as f32) as i32,
+            Scale::uniform(scale),
+            &font,
+            character,
+        );
+    }
 
-    let canvas = DynamicImage::from(canvas).rotate270();
+    // The top of the stack goes to the start of the label, then into printer orientation
+    Ok(DynamicImage::from(output).rotate180())
+}
+
+/// Scale an arbitrary image down onto the label canvas, preserving aspect ratio.
+/// The result uses the same orientation and polarity as `generate_image`:
+/// dark pixels in the source become ink (bright) in the output.
+pub fn fit_image(image: &DynamicImage) -> DynamicImage {
+    fit_image_sized(image, default_canvas())
+}
+
+/// Like `fit_image`, for a label canvas from `ModelSpec::canvas`. The image is fit into the printable area.
+/// On auto length canvases, it is scaled to the tape width and the label is as long as the image.
+pub fn fit_image_sized(image: &DynamicImage, canvas: Canvas) -> DynamicImage {
+    fit_image_with(image, canvas, &FitOptions::default())
+}
 
-    Ok(canvas)
+/// Add blank feed after a label from `generate_image` or `fit_image`, so labels on continuous tape
+/// don't run into each other. With `cut_mark`, a dashed line is drawn across the middle of the gap.
+/// The label's end is in its first rows in printer orientation, so that's where the gap goes.
+pub fn append_separator(image: &DynamicImage, spacing: u32, cut_mark: bool) -> DynamicImage {
+    // A cut mark needs a little room on both sides
+    let spacing = if cut_mark { spacing.max(8) } else { spacing };
+    if spacing == 0 {
+        return image.clone();
+    }
+    let mut output: ImageBuffer<Rgb<u8>, _> =
+        ImageBuffer::new(image.width(), image.height() + spacing);
+    image::imageops::overlay(&mut output, &image.to_rgb8(), 0, spacing as i64);
+    if cut_mark {
+        let y = spacing / 2;
+        for x in (0..image.width()).filter(|x| x % 8 < 4) {
+            output.put_pixel(x, y, COLOR_BLACK);
+        }
+    }
+    DynamicImage::from(output)
 }
 
 pub fn pack_image(image: &DynamicImage) -> Vec<u8> {
     // This section of code is heavily based on logic from polskafan's phomemo_d30 code on Github
     // See here: https://github.com/polskafan/phomemo_d30
-    let threshold: u8 = 127;
+    let threshold: u8 = PACK_THRESHOLD;
     let width = image.width() as usize;
     let height = image.height() as usize;
 
     let mut bit_grid = vec![vec![0u8; width]; height];
 
-    let image = image.to_rgb8();
+    // Luminance, so colored ink counts as much as it looks like it should. Transparent pixels are blank
+    let image = to_gray(image, Rgb([0, 0, 0]), Grayscale::Luma);
 
     let mut output = Vec::new();
     for (x, y, pixel) in image.enumerate_pixels() {
@@ -143,7 +264,57 @@ pub fn pack_image(image: &DynamicImage) -> Vec<u8> {
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct D30Config {
     pub default_device: Option<String>,
-    pub resolution: IndexMap<String, MacAddr6>,
+    pub resolution: IndexMap<String, DeviceEntry>,
+    /// Custom media profiles, by name
+    #[serde(default)]
+    pub media: IndexMap<String, Media>,
+}
+
+/// A `resolution` entry: either just an address, or a table with per-device settings
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum DeviceEntry {
+    Addr(MacAddr6),
+    Device(DeviceConfig),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub addr: MacAddr6,
+    #[serde(default)]
+    pub model: Model,
+    /// Media profile used when none is given, e.g. `12x40`
+    pub media: Option<String>,
+    /// Shift of everything printed on this device, in mm towards the end of the label,
+    /// to make up for a print head that is a little off. Set by `d30-cli calibrate`
+    #[serde(default)]
+    pub offset_x_mm: f32,
+    /// Like `offset_x_mm`, towards the bottom of the label as it is read
+    #[serde(default)]
+    pub offset_y_mm: f32,
+}
+
+impl DeviceEntry {
+    pub fn addr(&self) -> MacAddr6 {
+        match self {
+            DeviceEntry::Addr(addr) => *addr,
+            DeviceEntry::Device(device) => device.addr,
+        }
+    }
+
+    /// Settings for this device, with defaults filled in for bare addresses
+    pub fn config(&self) -> DeviceConfig {
+        match self {
+            DeviceEntry::Addr(addr) => DeviceConfig {
+                addr: *addr,
+                model: Model::default(),
+                media: None,
+                offset_x_mm: 0.0,
+                offset_y_mm: 0.0,
+            },
+            DeviceEntry::Device(device) => device.clone(),
+        }
+    }
 }
 
 #[derive(Debug, Snafu)]
@@ -162,26 +333,136 @@ pub enum D30Error {
     CouldNotPlaceConfigFile { source: io::Error },
     #[snafu(display("No default device specified"))]
     NoDefaultDevice,
+    #[snafu(display("Could not edit config file {}", path.display()))]
+    CouldNotEditConfig {
+        path: PathBuf,
+        #[snafu(source(from(toml_edit::TomlError, Box::new)))]
+        source: Box<toml_edit::TomlError>,
+    },
+    #[snafu(display("`{name}` in config file {} is not a table", path.display()))]
+    InvalidConfigEntry { path: PathBuf, name: String },
+    #[snafu(display("Could not write config file {}", path.display()))]
+    CouldNotWriteConfig { path: PathBuf, source: io::Error },
 
     #[snafu(display("Could not parse MAC address, or find in hostname table: {device}"))]
     CouldNotParseOrLookupMacAddress { device: String },
 
     #[snafu(display("Could not parse specified device as MAC address:\n"))]
     CouldNotParseMacAddress,
+
+    #[snafu(display("{message}"))]
+    InvalidMedia { message: String },
+    #[snafu(display("The {wrap} dot wrap zone leaves no room for flags on a {length} dot label"))]
+    WrapTooLong { wrap: u32, length: u32 },
+
+    #[snafu(display("Could not read template {}", path.display()))]
+    CouldNotReadTemplate { path: PathBuf, source: io::Error },
+    #[snafu(display("Invalid template {}", path.display()))]
+    InvalidTomlTemplate {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Invalid template {}", path.display()))]
+    InvalidJsonTemplate {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[snafu(display("No value given for template placeholder `{name}`"))]
+    MissingPlaceholder { name: String },
+    #[snafu(display("Could not load image {}", path.display()))]
+    CouldNotLoadTemplateImage {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    #[snafu(display("Could not load font {}", path.display()))]
+    CouldNotLoadTemplateFont { path: PathBuf, source: io::Error },
+    #[snafu(display("Not a usable font: {}", path.display()))]
+    InvalidTemplateFont { path: PathBuf },
+    #[snafu(display("Invalid barcode: {message}"))]
+    InvalidBarcode { message: String },
+
+    #[snafu(display("Invalid SVG"))]
+    InvalidSvg { source: resvg::usvg::Error },
+    #[snafu(display("SVG has no area to draw on"))]
+    EmptySvg,
+
+    #[snafu(display("Could not open Bluetooth socket ({kind})"))]
+    CouldNotOpenSocket {
+        kind: ConnectErrorKind,
+        source: BtError,
+    },
+    #[snafu(display("Could not connect to {addr} ({kind})"))]
+    CouldNotConnect {
+        addr: MacAddr6,
+        kind: ConnectErrorKind,
+        source: BtError,
+    },
+    #[snafu(display("Timed out after {timeout:?} while connecting to {addr}"))]
+    ConnectTimedOut { addr: MacAddr6, timeout: Duration },
+    #[snafu(display("Failed to connect to {addr} after {attempts} attempts"))]
+    RetriesExhausted {
+        addr: MacAddr6,
+        attempts: usize,
+        #[snafu(source(from(D30Error, Box::new)))]
+        source: Box<D30Error>,
+    },
+    #[snafu(display("Failed to poll Bluetooth socket"))]
+    Poll { source: io::Error },
+    #[snafu(display("Could not configure socket"))]
+    CouldNotConfigureSocket { source: io::Error },
+
+    #[snafu(display("The {model} is reached over {transport}, which is not supported yet"))]
+    UnsupportedTransport {
+        model: Model,
+        transport: TransportKind,
+    },
+    #[snafu(display("Printer is not connected (state: {state})"))]
+    NotConnected { state: PrinterState },
+    #[snafu(display("Failed to write to printer"))]
+    CouldNotWrite { source: io::Error },
+    #[snafu(display("Failed to read from printer"))]
+    CouldNotRead { source: io::Error },
+
+    #[snafu(display("Failed to write capture"))]
+    CouldNotWriteCapture { source: io::Error },
+    #[snafu(display("Failed to read capture"))]
+    CouldNotReadCapture { source: io::Error },
+    #[snafu(display("Invalid capture record on line {line}"))]
+    InvalidCaptureRecord { line: usize },
+    #[snafu(display("Not a capture: the first line isn't `{header}`"))]
+    NotACapture { header: String },
+}
+
+impl D30Error {
+    /// Whether the same operation might succeed if tried again later,
+    /// e.g. once a sleeping printer wakes up
+    pub fn is_recoverable(&self) -> bool {
+        match self {
+            Self::CouldNotOpenSocket { kind, .. } | Self::CouldNotConnect { kind, .. } => {
+                kind.is_recoverable()
+            }
+            Self::ConnectTimedOut { .. } | Self::RetriesExhausted { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 impl D30Config {
     pub fn load_toml(path: &PathBuf) -> Result<Self, D30Error> {
         let contents = fs::read_to_string(path).context(CouldNotReadFileSnafu)?;
-        Ok(toml::from_str(contents.as_str()).context(CouldNotParseSnafu)?)
+        toml::from_str(contents.as_str()).context(CouldNotParseSnafu)
     }
 
-    pub fn read_d30_config() -> Result<Self, D30Error> {
+    fn config_path() -> Result<PathBuf, D30Error> {
         let phomemo_lib_path = xdg::BaseDirectories::with_prefix("phomemo-library")
             .context(CouldNotGetXDGPathSnafu)?;
-        let config_path = phomemo_lib_path
+        phomemo_lib_path
             .place_config_file("phomemo-config.toml")
-            .context(CouldNotPlaceConfigFileSnafu)?;
+            .context(CouldNotPlaceConfigFileSnafu)
+    }
+
+    pub fn read_d30_config() -> Result<Self, D30Error> {
+        let config_path = Self::config_path()?;
         let toml = D30Config::load_toml(&config_path);
         if let Err(e) = &toml {
             warn!("Failed to parse config file: {:#?}", e);
@@ -196,17 +477,176 @@ impl D30Config {
             Err(e) => {
                 trace!("Device specification `{}` is not a MAC Address. Assuming it's a hostname, and attempting resolution.", printer_addr);
                 trace!("Inferred because: {}", e);
-                let mac = self.resolution.get(printer_addr).context(
+                let device = self.resolution.get(printer_addr).context(
                     CouldNotParseOrLookupMacAddressSnafu {
                         device: printer_addr,
                     },
                 )?;
-                Ok(*mac)
+                Ok(device.addr())
             }
         }
     }
 
+    /// Settings for the device with this address, if it's listed
+    pub fn device(&self, addr: MacAddr6) -> Option<DeviceConfig> {
+        self.resolution
+            .values()
+            .find(|device| device.addr() == addr)
+            .map(DeviceEntry::config)
+    }
+
+    /// Look up a media profile, custom ones from the config first
+    pub fn resolve_media(&self, name: &str) -> Result<Media, D30Error> {
+        match self.media.get(name) {
+            Some(media) => Ok(*media),
+            None => name
+                .parse()
+                .map_err(|message| InvalidMediaSnafu { message }.build()),
+        }
+    }
+
+    /// Store calibration offsets for the device with this address in the config file, keeping
+    /// the rest of the file as it is. Devices that aren't listed yet are added under their address.
+    /// Returns the path of the config file
+    pub fn save_offset(
+        addr: MacAddr6,
+        offset_x_mm: f32,
+        offset_y_mm: f32,
+    ) -> Result<PathBuf, D30Error> {
+        let path = Self::config_path()?;
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).context(CouldNotReadFileSnafu),
+        };
+        let contents = Self::with_offset(&contents, &path, addr, offset_x_mm, offset_y_mm)?;
+        fs::write(&path, contents).context(CouldNotWriteConfigSnafu { path: &path })?;
+        Ok(path)
+    }
+
+    /// `save_offset` on the contents of the config file at `path`
+    fn with_offset(
+        contents: &str,
+        path: &Path,
+        addr: MacAddr6,
+        offset_x_mm: f32,
+        offset_y_mm: f32,
+    ) -> Result<String, D30Error> {
+        let mut document = contents
+            .parse::<toml_edit::Document>()
+            .context(CouldNotEditConfigSnafu { path })?;
+        let resolution = document
+            .entry("resolution")
+            .or_insert(toml_edit::table())
+            .as_table_like_mut()
+            .context(InvalidConfigEntrySnafu {
+                path,
+                name: "resolution",
+            })?;
+        let entry_addr = |item: &toml_edit::Item| {
+            let addr = match item.as_table_like() {
+                Some(table) => table.get("addr")?.as_str(),
+                None => item.as_str(),
+            };
+            addr?.parse::<MacAddr6>().ok()
+        };
+        let name = resolution
+            .iter()
+            .find(|(_, item)| entry_addr(item) == Some(addr))
+            .map(|(name, _)| name.to_owned())
+            .unwrap_or_else(|| addr.format_string(advmac::MacAddrFormat::ColonNotation));
+        let entry = resolution.entry(&name).or_insert(toml_edit::value(
+            addr.format_string(advmac::MacAddrFormat::ColonNotation),
+        ));
+        // Bare addresses become tables, so there is room for the offsets. Comments stay
+        if let Some(bare) = entry.as_value() {
+            if let Some(bare_addr) = bare.as_str() {
+                let mut table = toml_edit::InlineTable::new();
+                table.insert("addr", bare_addr.into());
+                let mut table = toml_edit::Value::from(table);
+                *table.decor_mut() = bare.decor().clone();
+                *entry = toml_edit::Item::Value(table);
+            }
+        }
+        let device = entry.as_table_like_mut().context(InvalidConfigEntrySnafu {
+            path,
+            name: format!("resolution.{}", name),
+        })?;
+        // Rounded to a hundredth of a mm, so the file doesn't fill up with float noise
+        let round = |mm: f32| (mm as f64 * 100.0).round() / 100.0;
+        device.insert("offset_x_mm", toml_edit::value(round(offset_x_mm)));
+        device.insert("offset_y_mm", toml_edit::value(round(offset_y_mm)));
+        if let Some(table) = entry.as_inline_table_mut() {
+            table.fmt();
+        }
+        Ok(document.to_string())
+    }
+
     pub fn resolve_default(&self) -> Result<MacAddr6, D30Error> {
         self.resolve_addr(self.default_device.as_ref().context(NoDefaultDeviceSnafu)?)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"default_device = "kitchen"
+
+[resolution]
+# The one by the fridge
+kitchen = "DB:1E:B4:E7:A3:75"
+shipping = { addr = "E9:7B:61:9E:76:47", model = "m02", offset_x_mm = 1.0 }
+office = "11:22:33:44:55:66"
+"#;
+
+    fn mac(addr: &str) -> MacAddr6 {
+        addr.parse().unwrap()
+    }
+
+    fn save(contents: &str, addr: &str, x: f32, y: f32) -> D30Config {
+        let contents =
+            D30Config::with_offset(contents, Path::new("config.toml"), mac(addr), x, y).unwrap();
+        toml::from_str(&contents).unwrap()
+    }
+
+    #[test]
+    fn save_offset_turns_plain_entries_into_tables() {
+        let config = save(CONFIG, "DB:1E:B4:E7:A3:75", 0.5, -0.25);
+        let kitchen = config.device(mac("DB:1E:B4:E7:A3:75")).unwrap();
+        assert_eq!((kitchen.offset_x_mm, kitchen.offset_y_mm), (0.5, -0.25));
+        // The others are left alone, plain or not
+        assert!(matches!(config.resolution["office"], DeviceEntry::Addr(_)));
+        let shipping = config.device(mac("E9:7B:61:9E:76:47")).unwrap();
+        assert_eq!((shipping.model, shipping.offset_x_mm), (Model::M02, 1.0));
+        assert_eq!(config.default_device.as_deref(), Some("kitchen"));
+    }
+
+    #[test]
+    fn save_offset_updates_detailed_entries() {
+        let config = save(CONFIG, "E9:7B:61:9E:76:47", -1.0, 2.0);
+        let shipping = config.device(mac("E9:7B:61:9E:76:47")).unwrap();
+        assert_eq!(shipping.model, Model::M02);
+        assert_eq!((shipping.offset_x_mm, shipping.offset_y_mm), (-1.0, 2.0));
+        for plain in ["kitchen", "office"] {
+            assert!(matches!(config.resolution[plain], DeviceEntry::Addr(_)));
+        }
+    }
+
+    #[test]
+    fn save_offset_keeps_comments_and_adds_new_devices() {
+        let contents = D30Config::with_offset(
+            CONFIG,
+            Path::new("config.toml"),
+            mac("AA:BB:CC:DD:EE:FF"),
+            0.1,
+            0.0,
+        )
+        .unwrap();
+        assert!(contents.contains("# The one by the fridge"));
+        let config: D30Config = toml::from_str(&contents).unwrap();
+        assert_eq!(config.resolution.len(), 4);
+        let new = config.device(mac("AA:BB:CC:DD:EE:FF")).unwrap();
+        assert_eq!(new.offset_x_mm, 0.1);
+    }
+}
@@ -0,0 +1,228 @@
+//! Label stock, and the pixel canvas it translates to on a given printer.
+
+use std::{fmt, str::FromStr};
+
+use derive_more::Display;
+use imageproc::rect::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::{Length, Margins, Unit};
+
+/// Border the print head can't reliably reach on die-cut labels, in mm
+const DIE_CUT_MARGIN_MM: f32 = 0.5;
+
+/// A label size. Width is across the print head, length along the feed direction
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Media {
+    pub width_mm: f32,
+    /// `None` for continuous rolls
+    #[serde(default)]
+    pub length_mm: Option<f32>,
+    /// Border on every side that is left blank, in mm
+    #[serde(default)]
+    pub margin_mm: f32,
+    /// Labels are separated by black marks on the back instead of gaps
+    #[serde(default)]
+    pub black_mark: bool,
+}
+
+/// How the printer finds the start of the next label
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MediaKind {
+    #[default]
+    #[display(fmt = "labels with gaps")]
+    Gaps,
+    #[display(fmt = "continuous")]
+    Continuous,
+    #[display(fmt = "labels with black marks")]
+    BlackMark,
+}
+
+impl MediaKind {
+    /// Argument to the `1f 11` command that sets the media type
+    pub fn code(self) -> u8 {
+        match self {
+            MediaKind::Gaps => 0x0a,
+            MediaKind::Continuous => 0x0b,
+            MediaKind::BlackMark => 0x26,
+        }
+    }
+}
+
+/// Named profiles for common label stock, as accepted by `--media`
+pub const PROFILES: &[(&str, Media)] = &[
+    ("12x30", Media::new(12.0, 30.0)),
+    ("12x40", Media::new(12.0, 40.0)),
+    ("14x30", Media::new(14.0, 30.0)),
+    ("14x40", Media::new(14.0, 40.0)),
+    ("15x30", Media::new(15.0, 30.0)),
+    ("15x50", Media::new(15.0, 50.0)),
+];
+
+impl Media {
+    /// Die-cut labels of the given size
+    pub const fn new(width_mm: f32, length_mm: f32) -> Self {
+        Self {
+            width_mm,
+            length_mm: Some(length_mm),
+            margin_mm: DIE_CUT_MARGIN_MM,
+            black_mark: false,
+        }
+    }
+
+    /// Endless tape, cut to whatever length the content needs
+    pub const fn continuous(width_mm: f32) -> Self {
+        Self {
+            width_mm,
+            length_mm: None,
+            margin_mm: 0.0,
+            black_mark: false,
+        }
+    }
+
+    pub fn kind(&self) -> MediaKind {
+        match (self.length_mm, self.black_mark) {
+            (None, _) => MediaKind::Continuous,
+            (Some(_), true) => MediaKind::BlackMark,
+            (Some(_), false) => MediaKind::Gaps,
+        }
+    }
+}
+
+impl fmt::Display for Media {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.length_mm {
+            Some(length_mm) => write!(f, "{}x{}", self.width_mm, length_mm),
+            None => write!(f, "{} continuous", self.width_mm),
+        }
+    }
+}
+
+/// Accepts profile names, `<width>x<length>` in mm for other label sizes,
+/// and a bare `<width>` in mm for continuous tape
+impl FromStr for Media {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((_, media)) = PROFILES.iter().find(|(name, _)| *name == s) {
+            return Ok(*media);
+        }
+        let invalid = || {
+            format!(
+                "Invalid media: {}. Expected a profile name, `<width>x<length>` or `<width>` in mm",
+                s
+            )
+        };
+        // `nan` and `inf` parse as numbers too
+        let size = |mm: &str| {
+            mm.trim()
+                .parse::<f32>()
+                .ok()
+                .filter(|mm| mm.is_finite() && *mm > 0.0)
+                .ok_or_else(invalid)
+        };
+        match s.split_once('x') {
+            Some((width, length)) => Ok(Media::new(size(width)?, size(length)?)),
+            None => Ok(Media::continuous(size(s)?)),
+        }
+    }
+}
+
+/// Pixel layout of a label, in reading orientation: `width` runs along the tape, `height` across it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Canvas {
+    pub width: u32,
+    pub height: u32,
+    /// The part of the canvas that ends up on the label
+    pub printable: Rect,
+    /// `width` is only a starting point. Rendering resizes the canvas to fit the content, e.g. on continuous tape
+    pub auto_length: bool,
+    /// Resolution of the printer, for lengths in mm or pt
+    pub dpi: u32,
+}
+
+impl Canvas {
+    /// A canvas that is printable up to the edges
+    pub fn new(width: u32, height: u32, dpi: u32) -> Self {
+        Self {
+            width,
+            height,
+            printable: Rect::at(0, 0).of_size(width.max(1), height.max(1)),
+            auto_length: false,
+            dpi,
+        }
+    }
+
+    /// The same canvas with a different `width`, keeping the blank space at both ends
+    pub fn resized(&self, width: u32) -> Self {
+        let width = width.max(1);
+        let ends = self.width.saturating_sub(self.printable.width());
+        Self {
+            width,
+            printable: Rect::at(self.printable.left(), self.printable.top())
+                .of_size(width.saturating_sub(ends).max(1), self.printable.height()),
+            ..*self
+        }
+    }
+
+    /// The same canvas turned by 90°. Auto length canvases become fixed, since their length
+    /// would run across the content
+    pub fn transposed(&self) -> Self {
+        Self {
+            width: self.height,
+            height: self.width,
+            printable: Rect::at(self.printable.top(), self.printable.left())
+                .of_size(self.printable.height(), self.printable.width()),
+            auto_length: false,
+            dpi: self.dpi,
+        }
+    }
+
+    /// Resize so the content area for `margins` is exactly `length` pixels long
+    pub fn fit_length(&self, margins: &Margins, length: u32) -> Self {
+        let ends = self.width - self.content_area(margins).width();
+        self.resized(length + ends)
+    }
+
+    /// Area left for content when keeping `margins` from the edges, and never outside the printable area.
+    /// Margins without a unit are in pixels
+    pub fn content_area(&self, margins: &Margins) -> Rect {
+        let dots = |length: Length| length.dots(self.dpi, Unit::Px).max(0.0).round() as i32;
+        let left = self.printable.left().max(dots(margins.left));
+        let top = self.printable.top().max(dots(margins.top));
+        let right = self
+            .printable
+            .right()
+            .min(self.width as i32 - 1 - dots(margins.right));
+        let bottom = self
+            .printable
+            .bottom()
+            .min(self.height as i32 - 1 - dots(margins.bottom));
+        Rect::at(left, top).of_size(
+            (right - left + 1).max(1) as u32,
+            (bottom - top + 1).max(1) as u32,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!("12x40".parse(), Ok(Media::new(12.0, 40.0)));
+        assert_eq!(" 14 x 30.5 ".parse(), Ok(Media::new(14.0, 30.5)));
+        assert_eq!("12".parse(), Ok(Media::continuous(12.0)));
+    }
+
+    #[test]
+    fn rejects_sizes_that_are_not_positive_numbers() {
+        for junk in [
+            "", "x", "0", "-3", "12x0", "12x-40", "nan", "inf", "NaNx40", "12xinf", "12x40x2",
+        ] {
+            assert!(junk.parse::<Media>().is_err(), "{:?}", junk);
+        }
+    }
+}
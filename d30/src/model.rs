@@ -0,0 +1,323 @@
+//! Phomemo printers this crate knows how to drive.
+//!
+//! Only the D30 has been confirmed against real hardware. The other entries are
+//! based on the protocol notes of other Phomemo projects, and may need corrections.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use imageproc::rect::Rect;
+use serde::{Deserialize, Serialize};
+
+use crate::{Canvas, D30Error, InvalidMediaSnafu, Media, MediaKind};
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Model {
+    #[default]
+    D30,
+    D35,
+    D50,
+    Q30,
+    M02,
+    M110,
+}
+
+impl Model {
+    pub const ALL: &'static [Model] = &[
+        Model::D30,
+        Model::D35,
+        Model::D50,
+        Model::Q30,
+        Model::M02,
+        Model::M110,
+    ];
+
+    pub fn spec(self) -> &'static ModelSpec {
+        match self {
+            Model::D30 => &D30,
+            Model::D35 => &D35,
+            Model::D50 => &D50,
+            Model::Q30 => &Q30,
+            Model::M02 => &M02,
+            Model::M110 => &M110,
+        }
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Model::ALL
+            .iter()
+            .find(|model| model.to_string().eq_ignore_ascii_case(s))
+            .copied()
+            .ok_or_else(|| format!("Unknown printer model: {}", s))
+    }
+}
+
+/// How the printer is reached
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    #[display(fmt = "Bluetooth (RFCOMM)")]
+    Rfcomm,
+    /// Not supported by `connect` yet. Use `D30Printer::attach` with your own transport
+    #[display(fmt = "Bluetooth LE")]
+    Ble,
+}
+
+/// How far to trust a model's entry
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Support {
+    /// Tested on real hardware
+    #[display(fmt = "tested")]
+    Tested,
+    /// Taken from other projects' protocol notes, unverified
+    #[display(fmt = "experimental")]
+    Experimental,
+    /// `connect` can't reach it yet, because it uses Bluetooth LE
+    #[display(fmt = "unsupported")]
+    Unsupported,
+}
+
+#[derive(Debug)]
+pub struct ModelSpec {
+    pub model: Model,
+    pub support: Support,
+    /// Dots across the print head
+    pub width_dots: u32,
+    pub dpi: u32,
+    /// The first entry is what the printer usually ships with
+    pub media: &'static [Media],
+    /// Sent once after connecting
+    pub init: &'static [u8],
+    /// Sent before the raster header of every label
+    pub job_header: &'static [u8],
+    /// Sent after the raster data of every label
+    pub job_footer: &'static [u8],
+    /// Command that sets the media type, completed by `MediaKind::code`. `None` if there is none.
+    /// Only sent for media other than labels with gaps, which the printers start out with
+    pub media_command: Option<&'static [u8]>,
+    pub transport: TransportKind,
+}
+
+/// Starting length for continuous media, before the content decides
+const CONTINUOUS_LENGTH_MM: f32 = 40.0;
+
+impl ModelSpec {
+    pub fn dots(&self, mm: f32) -> u32 {
+        (mm * self.dpi as f32 / 25.4).round() as u32
+    }
+
+    pub fn default_media(&self) -> Media {
+        self.media[0]
+    }
+
+    /// Make sure `media` comes out at least a dot wide and long on this printer. Media wider
+    /// than the print head is fine, `canvas` only uses the part under the head
+    pub fn check_media(&self, media: &Media) -> Result<(), D30Error> {
+        let too_small = |mm: f32| !mm.is_finite() || self.dots(mm) == 0;
+        if too_small(media.width_mm) || media.length_mm.is_some_and(too_small) {
+            return InvalidMediaSnafu {
+                message: format!(
+                    "{} is less than a dot wide or long on the {}, at {} dpi",
+                    media, self.model, self.dpi
+                ),
+            }
+            .fail();
+        }
+        Ok(())
+    }
+
+    /// Pixel canvas for a label on this printer.
+    /// The height is capped at the print head and rounded up to whole bytes.
+    pub fn canvas(&self, media: &Media) -> Canvas {
+        let width = self.dots(media.length_mm.unwrap_or(CONTINUOUS_LENGTH_MM));
+        let height = self.dots(media.width_mm).min(self.width_dots).div_ceil(8) * 8;
+        let margin = self.dots(media.margin_mm);
+        let mut canvas = Canvas::new(width, height, self.dpi);
+        canvas.auto_length = media.length_mm.is_none();
+        if width > 2 * margin && height > 2 * margin {
+            canvas.printable = Rect::at(margin as i32, margin as i32)
+                .of_size(width - 2 * margin, height - 2 * margin);
+        }
+        canvas
+    }
+
+    /// Everything that goes in front of the raster data of a `width_bytes * 8` by `height` label
+    pub fn raster_header(&self, width_bytes: u16, height: u16) -> Vec<u8> {
+        let mut header = self.job_header.to_vec();
+        // GS v 0, normal mode
+        header.extend_from_slice(&[0x1d, 0x76, 0x30, 0x00]);
+        header.extend_from_slice(&width_bytes.to_le_bytes());
+        header.extend_from_slice(&height.to_le_bytes());
+        header
+    }
+
+    /// The header of a label on `media_kind`. Labels with gaps get the plain `raster_header`, as
+    /// no capture of the vendor app shows it sending the media command for them
+    pub fn label_header(&self, media_kind: MediaKind, width_bytes: u16, height: u16) -> Vec<u8> {
+        let mut header = Vec::new();
+        if let Some(media_command) = self.media_command.filter(|_| media_kind != MediaKind::Gaps) {
+            header.extend_from_slice(media_command);
+            header.push(media_kind.code());
+        }
+        header.extend(self.raster_header(width_bytes, height));
+        header
+    }
+}
+
+const D_SERIES_MEDIA: &[Media] = &[
+    Media::new(12.0, 40.0),
+    Media::new(12.0, 30.0),
+    Media::new(14.0, 30.0),
+    Media::new(14.0, 40.0),
+    Media::new(15.0, 30.0),
+    Media::new(15.0, 50.0),
+];
+
+const WIDE_D_SERIES_MEDIA: &[Media] = &[
+    Media::new(25.0, 50.0),
+    Media::new(12.0, 40.0),
+    Media::new(15.0, 50.0),
+    Media::new(20.0, 50.0),
+    Media::new(25.0, 30.0),
+];
+
+// 1f1124 00, then ESC @
+const D_SERIES_JOB_HEADER: &[u8] = &[0x1f, 0x11, 0x24, 0x00, 0x1b, 0x40];
+
+const D30: ModelSpec = ModelSpec {
+    model: Model::D30,
+    support: Support::Tested,
+    width_dots: 96,
+    dpi: 203,
+    media: D_SERIES_MEDIA,
+    init: crate::INIT_BASE_FLAT,
+    job_header: D_SERIES_JOB_HEADER,
+    job_footer: &[],
+    media_command: Some(&[0x1f, 0x11]),
+    transport: TransportKind::Rfcomm,
+};
+
+const Q30: ModelSpec = ModelSpec {
+    model: Model::Q30,
+    support: Support::Experimental,
+    ..D30
+};
+
+const D35: ModelSpec = ModelSpec {
+    model: Model::D35,
+    support: Support::Unsupported,
+    width_dots: 192,
+    media: WIDE_D_SERIES_MEDIA,
+    transport: TransportKind::Ble,
+    ..D30
+};
+
+const D50: ModelSpec = ModelSpec {
+    model: Model::D50,
+    support: Support::Unsupported,
+    width_dots: 240,
+    media: WIDE_D_SERIES_MEDIA,
+    transport: TransportKind::Ble,
+    ..D30
+};
+
+// Based on https://github.com/theacodes/phomemo_m02s
+const M02: ModelSpec = ModelSpec {
+    model: Model::M02,
+    support: Support::Experimental,
+    width_dots: 384,
+    dpi: 203,
+    media: &[Media::continuous(53.0)],
+    init: &[0x1b, 0x40],
+    // ESC @, centered, 1f1102 04 (density?)
+    job_header: &[0x1b, 0x40, 0x1b, 0x61, 0x01, 0x1f, 0x11, 0x02, 0x04],
+    // Feed twice, then the same queries the app sends
+    job_footer: &[
+        0x1b, 0x64, 0x02, 0x1b, 0x64, 0x02, 0x1f, 0x11, 0x08, 0x1f, 0x11, 0x0e, 0x1f, 0x11, 0x07,
+        0x1f, 0x11, 0x09,
+    ],
+    media_command: None,
+    transport: TransportKind::Rfcomm,
+};
+
+// Based on https://github.com/vivier/phomemo-tools
+const M110: ModelSpec = ModelSpec {
+    model: Model::M110,
+    support: Support::Unsupported,
+    width_dots: 384,
+    dpi: 203,
+    media: &[
+        Media::new(40.0, 30.0),
+        Media::new(30.0, 20.0),
+        Media::new(50.0, 30.0),
+    ],
+    init: &[],
+    // Speed 5, density 10
+    job_header: &[0x1b, 0x4e, 0x0d, 0x05, 0x1b, 0x4e, 0x04, 0x0a],
+    job_footer: &[0x1f, 0xf0, 0x05, 0x00, 0x1f, 0xf0, 0x03, 0x00],
+    media_command: Some(&[0x1f, 0x11]),
+    transport: TransportKind::Ble,
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn media_has_to_be_at_least_a_dot() {
+        let spec = Model::D30.spec();
+        assert!(spec.check_media(&Media::new(12.0, 40.0)).is_ok());
+        assert!(spec.check_media(&Media::continuous(12.0)).is_ok());
+        assert!(spec.check_media(&Media::continuous(0.01)).is_err());
+        assert!(spec.check_media(&Media::new(12.0, 0.05)).is_err());
+        assert!(spec.check_media(&Media::new(f32::NAN, 40.0)).is_err());
+    }
+
+    #[test]
+    fn label_header_bytes() {
+        let spec = Model::D30.spec();
+        let raster = [
+            0x1f, 0x11, 0x24, 0x00, 0x1b, 0x40, 0x1d, 0x76, 0x30, 0x00, 0x0c, 0x00, 0x40, 0x01,
+        ];
+        assert_eq!(spec.label_header(MediaKind::Gaps, 12, 320), raster);
+        assert_eq!(
+            spec.label_header(MediaKind::Continuous, 12, 320),
+            [&[0x1f, 0x11, 0x0b][..], &raster].concat()
+        );
+        let m02 = Model::M02.spec();
+        assert_eq!(
+            m02.label_header(MediaKind::Continuous, 12, 320),
+            m02.raster_header(12, 320)
+        );
+    }
+
+    #[test]
+    fn d30_canvas() {
+        let canvas = Model::D30.spec().canvas(&Media::new(12.0, 40.0));
+        assert_eq!((canvas.width, canvas.height), (320, 96));
+    }
+
+    #[test]
+    fn only_rfcomm_models_are_supported() {
+        for model in Model::ALL {
+            let spec = model.spec();
+            assert_eq!(
+                spec.support == Support::Unsupported,
+                spec.transport == TransportKind::Ble,
+                "{}",
+                model
+            );
+        }
+    }
+
+    #[test]
+    fn media_wider_than_the_head_is_capped() {
+        let spec = Model::D30.spec();
+        assert!(spec.check_media(&Media::new(20.0, 40.0)).is_ok());
+        assert_eq!(spec.canvas(&Media::new(20.0, 40.0)).height, spec.width_dots);
+    }
+}
@@ -0,0 +1,173 @@
+//! Rotating and mirroring label content.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::{imageops, DynamicImage};
+
+use crate::Canvas;
+
+/// Clockwise rotation of the content on the label
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Rotation {
+    #[default]
+    #[display(fmt = "0")]
+    R0,
+    #[display(fmt = "90")]
+    R90,
+    #[display(fmt = "180")]
+    R180,
+    #[display(fmt = "270")]
+    R270,
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(Self::R0),
+            "90" => Ok(Self::R90),
+            "180" => Ok(Self::R180),
+            "270" => Ok(Self::R270),
+            _ => Err(format!(
+                "Invalid rotation: {}. Expected `0`, `90`, `180` or `270`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Mirror {
+    #[default]
+    #[display(fmt = "none")]
+    None,
+    /// Left and right swapped, e.g. for labels read from the back through glass
+    #[display(fmt = "horizontal")]
+    Horizontal,
+    /// Upside down, but not rotated
+    #[display(fmt = "vertical")]
+    Vertical,
+    #[display(fmt = "both")]
+    Both,
+}
+
+impl FromStr for Mirror {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "horizontal" => Ok(Self::Horizontal),
+            "vertical" => Ok(Self::Vertical),
+            "both" => Ok(Self::Both),
+            _ => Err(format!(
+                "Invalid mirror mode: {}. Expected `none`, `horizontal`, `vertical` or `both`",
+                s
+            )),
+        }
+    }
+}
+
+/// Rotation first, then mirroring, both as seen when reading the label
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Orientation {
+    pub rotation: Rotation,
+    pub mirror: Mirror,
+}
+
+impl Orientation {
+    fn is_sideways(&self) -> bool {
+        matches!(self.rotation, Rotation::R90 | Rotation::R270)
+    }
+
+    /// Canvas to render the content on, so it fits `canvas` once `apply` has turned it
+    pub fn canvas(&self, canvas: Canvas) -> Canvas {
+        if self.is_sideways() {
+            canvas.transposed()
+        } else {
+            canvas
+        }
+    }
+
+    /// Rotate and mirror a label from `generate_image`, `fit_image` and the like,
+    /// that was rendered on the canvas from `Orientation::canvas`
+    pub fn apply(&self, label: &DynamicImage) -> DynamicImage {
+        // Back to reading orientation
+        let content = label.rotate90();
+        let content = match self.rotation {
+            Rotation::R0 => content,
+            Rotation::R90 => content.rotate90(),
+            Rotation::R180 => content.rotate180(),
+            Rotation::R270 => content.rotate270(),
+        };
+        let mut content = content.to_rgb8();
+        if matches!(self.mirror, Mirror::Horizontal | Mirror::Both) {
+            imageops::flip_horizontal_in_place(&mut content);
+        }
+        if matches!(self.mirror, Mirror::Vertical | Mirror::Both) {
+            imageops::flip_vertical_in_place(&mut content);
+        }
+        DynamicImage::from(content).rotate270()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::RgbImage;
+
+    use crate::COLOR_BLACK;
+
+    const ROTATIONS: [Rotation; 4] = [Rotation::R0, Rotation::R90, Rotation::R180, Rotation::R270];
+    const MIRRORS: [Mirror; 4] = [
+        Mirror::None,
+        Mirror::Horizontal,
+        Mirror::Vertical,
+        Mirror::Both,
+    ];
+
+    /// Whether the top left corner ends up on the right and at the bottom, as read
+    fn expected_corner(orientation: Orientation) -> (bool, bool) {
+        let (right, bottom) = match orientation.rotation {
+            Rotation::R0 => (false, false),
+            Rotation::R90 => (true, false),
+            Rotation::R180 => (true, true),
+            Rotation::R270 => (false, true),
+        };
+        let (flip_x, flip_y) = match orientation.mirror {
+            Mirror::None => (false, false),
+            Mirror::Horizontal => (true, false),
+            Mirror::Vertical => (false, true),
+            Mirror::Both => (true, true),
+        };
+        (right != flip_x, bottom != flip_y)
+    }
+
+    #[test]
+    fn marker_ends_up_in_the_expected_corner() {
+        let canvas = Canvas::new(320, 96, 203);
+        for rotation in ROTATIONS {
+            for mirror in MIRRORS {
+                let orientation = Orientation { rotation, mirror };
+                let content_canvas = orientation.canvas(canvas);
+                let sideways = matches!(rotation, Rotation::R90 | Rotation::R270);
+                let size = (content_canvas.width, content_canvas.height);
+                assert_eq!(size, if sideways { (96, 320) } else { (320, 96) });
+
+                // Rendered like a label: in reading orientation, then turned for the printer
+                let mut content = RgbImage::new(size.0, size.1);
+                content.put_pixel(0, 0, COLOR_BLACK);
+                let label = DynamicImage::from(content).rotate270();
+
+                let read = orientation.apply(&label).rotate90().to_rgb8();
+                assert_eq!(read.dimensions(), (320, 96), "{:?}", orientation);
+                let (right, bottom) = expected_corner(orientation);
+                let x = if right { read.width() - 1 } else { 0 };
+                let y = if bottom { read.height() - 1 } else { 0 };
+                assert_eq!(read.get_pixel(x, y), &COLOR_BLACK, "{:?}", orientation);
+            }
+        }
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    mem::ManuallyDrop,
+    os::unix::net::UnixStream,
+};
+
+use advmac::MacAddr6;
+use derive_more::Display;
+use image::{imageops, DynamicImage, RgbImage};
+use log::{debug, info};
+use snafu::ResultExt;
+
+use crate::{
+    capture::{CaptureWriter, Direction},
+    connection, CouldNotConfigureSocketSnafu, CouldNotReadSnafu, CouldNotWriteSnafu, D30Error,
+    MediaKind, Model, NotConnectedSnafu, RetryPolicy, TransportKind, UnsupportedTransportSnafu,
+};
+
+/// Anything a printer can be driven over. Usually a `BtSocket`,
+/// but e.g. an in-memory buffer works for dry runs.
+pub trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum PrinterState {
+    Disconnected,
+    Connecting,
+    /// Connected and initialized, waiting for a label
+    Ready,
+    Printing,
+    /// The last operation failed. The connection is dropped, and `connect` starts over
+    Error,
+}
+
+/// A connection to a single printer, initialized once and reusable for any number of labels.
+///
+/// ```no_run
+/// # fn main() -> Result<(), d30::D30Error> {
+/// let addr = "40:5B:A4:2F:05:46".parse().unwrap();
+/// let mut printer = d30::D30Printer::new(addr, d30::RetryPolicy::default());
+/// printer.connect()?;
+/// let margins = d30::Margins::uniform(d30::Length::mm(2.0));
+/// let scale = d30::D30Scale::Auto { min: None, max: None };
+/// for text in ["Flour", "Sugar"] {
+///     let label = d30::generate_image(text, &margins, scale)?;
+///     printer.print(&label, 1)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct D30Printer {
+    addr: MacAddr6,
+    model: Model,
+    media_kind: MediaKind,
+    policy: RetryPolicy,
+    transport: Option<Box<dyn Transport>>,
+    /// The socket behind `transport` if `connect` opened it, to check for responses without
+    /// blocking. It shares the socket's file descriptor, so it must never be dropped (and close it)
+    socket: Option<ManuallyDrop<UnixStream>>,
+    state: PrinterState,
+    capture: Option<CaptureWriter>,
+    /// Calibration offset in mm, along the label and across it
+    offset: (f32, f32),
+}
+
+impl D30Printer {
+    pub fn new(addr: MacAddr6, policy: RetryPolicy) -> Self {
+        Self {
+            addr,
+            model: Model::default(),
+            media_kind: MediaKind::Gaps,
+            policy,
+            transport: None,
+            socket: None,
+            state: PrinterState::Disconnected,
+            capture: None,
+            offset: (0.0, 0.0),
+        }
+    }
+
+    /// Record all traffic from now on, including the init sequence if not connected yet.
+    /// While a capture is active, responses are collected between the chunks of every label, and
+    /// after it
+    pub fn capture_to(&mut self, capture: CaptureWriter) {
+        self.capture = Some(capture);
+    }
+
+    /// Which printer model to speak to. Defaults to the D30.
+    /// Takes effect on the next `connect` or `attach`
+    pub fn set_model(&mut self, model: Model) {
+        self.model = model;
+    }
+
+    /// What kind of media is loaded. Sent to the printer with every label, unless it's labels
+    /// with gaps
+    pub fn set_media_kind(&mut self, kind: MediaKind) {
+        self.media_kind = kind;
+    }
+
+    /// Shift every label by this many mm towards its end (`x`) and its bottom (`y`), as read,
+    /// to make up for a printer that prints off-center. See `DeviceConfig::offset_x_mm`
+    pub fn set_offset(&mut self, offset_x_mm: f32, offset_y_mm: f32) {
+        self.offset = (offset_x_mm, offset_y_mm);
+    }
+
+    /// The label moved by the calibration offset. What moves past the edges is cut off
+    fn shift(&self, image: &DynamicImage) -> DynamicImage {
+        if self.offset == (0.0, 0.0) {
+            return image.clone();
+        }
+        let dpi = self.model.spec().dpi as f32;
+        let [x, y] = [self.offset.0, self.offset.1].map(|mm| (mm * dpi / 25.4).round() as i64);
+        let mut output = RgbImage::new(image.width(), image.height());
+        // In printer orientation, the label's start is at the bottom
+        imageops::overlay(&mut output, &image.to_rgb8(), y, -x);
+        DynamicImage::from(output)
+    }
+
+    pub fn addr(&self) -> MacAddr6 {
+        self.addr
+    }
+
+    pub fn model(&self) -> Model {
+        self.model
+    }
+
+    pub fn state(&self) -> PrinterState {
+        self.state
+    }
+
+    /// Connect over Bluetooth, following the retry policy, and initialize the printer
+    pub fn connect(&mut self) -> Result<(), D30Error> {
+        self.close(self.state);
+        let transport = self.model.spec().transport;
+        if transport != TransportKind::Rfcomm {
+            self.state = PrinterState::Error;
+            return UnsupportedTransportSnafu {
+                model: self.model,
+                transport,
+            }
+            .fail();
+        }
+        self.state = PrinterState::Connecting;
+        match connection::connect(self.addr, &self.policy) {
+            Ok(socket) => {
+                let stream = ManuallyDrop::new(socket.get_stream_std());
+                self.attach_transport(Box::new(socket), Some(stream))
+            }
+            Err(e) => {
+                self.state = PrinterState::Error;
+                Err(e)
+            }
+        }
+    }
+
+    /// Drive the printer over an already open transport instead of connecting over Bluetooth.
+    /// The init sequence is sent immediately.
+    pub fn attach(&mut self, transport: Box<dyn Transport>) -> Result<(), D30Error> {
+        self.attach_transport(transport, None)
+    }
+
+    fn attach_transport(
+        &mut self,
+        transport: Box<dyn Transport>,
+        socket: Option<ManuallyDrop<UnixStream>>,
+    ) -> Result<(), D30Error> {
+        self.transport = Some(transport);
+        self.socket = socket;
+        debug!("Init connection");
+        let init = self.model.spec().init;
+        if !init.is_empty() {
+            self.write(init)?;
+        }
+        info!("Printer {} ready", self.addr);
+        self.state = PrinterState::Ready;
+        Ok(())
+    }
+
+    pub fn disconnect(&mut self) {
+        self.close(PrinterState::Disconnected);
+    }
+
+    /// Drop the transport, and the socket that goes with it
+    fn close(&mut self, state: PrinterState) {
+        self.socket = None;
+        self.transport = None;
+        self.state = state;
+    }
+
+    /// Print `copies` copies of an image from `generate_image` or `fit_image`,
+    /// sized for this printer model.
+    /// Connects first if there's no usable connection yet.
+    pub fn print(&mut self, image: &DynamicImage, copies: u32) -> Result<(), D30Error> {
+        if matches!(self.state, PrinterState::Disconnected | PrinterState::Error) {
+            self.connect()?;
+        }
+        self.state = PrinterState::Printing;
+
+        let image = &self.shift(image);
+        let spec = self.model.spec();
+        let header = spec.label_header(
+            self.media_kind,
+            (image.width() / 8) as u16,
+            image.height() as u16,
+        );
+        // Image must be send in chunks of 255 lines
+        let chunks = image.height() / 255;
+        for image_num in 0..copies {
+            debug!("Printing copy #{}", image_num);
+            let mut output = header.clone();
+            for chunk_num in 0..=chunks {
+                let chunk = image.clone().crop(0, chunk_num * 255, image.width(), 255);
+                output.extend(crate::pack_image(&chunk));
+                self.write(&output)?;
+                output.clear();
+                self.poll_responses()?;
+            }
+            if !spec.job_footer.is_empty() {
+                self.write(spec.job_footer)?;
+                self.poll_responses()?;
+            }
+        }
+
+        if self.capture.is_some() {
+            self.receive()?;
+        }
+        self.state = PrinterState::Ready;
+        Ok(())
+    }
+
+    /// Collect whatever the printer sent back, until it goes quiet for the transport's read timeout
+    pub fn receive(&mut self) -> Result<Vec<u8>, D30Error> {
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 256];
+        loop {
+            match self.read(&mut buffer)? {
+                0 => break,
+                len => received.extend_from_slice(&buffer[..len]),
+            }
+        }
+        if !received.is_empty() {
+            debug!("Received {} bytes", received.len());
+        }
+        Ok(received)
+    }
+
+    /// Capture responses that already came in, without waiting for more. Only sockets opened by
+    /// `connect` can be checked without blocking, so this does nothing for attached transports
+    fn poll_responses(&mut self) -> Result<(), D30Error> {
+        let socket = match (&self.socket, &self.capture) {
+            (Some(socket), Some(_)) => socket,
+            _ => return Ok(()),
+        };
+        socket
+            .set_nonblocking(true)
+            .context(CouldNotConfigureSocketSnafu)?;
+        let mut buffer = [0u8; 256];
+        let result = loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break Ok(()),
+                Ok(_) => continue,
+                Err(e) => break Err(e),
+            }
+        };
+        // A failed read drops the connection, and the socket with it
+        if let Some(socket) = &self.socket {
+            socket
+                .set_nonblocking(false)
+                .context(CouldNotConfigureSocketSnafu)?;
+        }
+        result
+    }
+
+    /// A single read, captured as soon as it's done so the capture shows when the bytes arrived.
+    /// Returns 0 once the printer has gone quiet
+    fn read(&mut self, buffer: &mut [u8]) -> Result<usize, D30Error> {
+        let transport = match &mut self.transport {
+            Some(transport) => transport,
+            None => return NotConnectedSnafu { state: self.state }.fail(),
+        };
+        let result = loop {
+            match transport.read(buffer) {
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    break Ok(0)
+                }
+                result => break result,
+            }
+        };
+        match result {
+            Ok(len) => {
+                if let (Some(capture), true) = (&mut self.capture, len > 0) {
+                    capture.record(Direction::Received, &buffer[..len])?;
+                }
+                Ok(len)
+            }
+            Err(e) => {
+                self.close(PrinterState::Error);
+                Err(e).context(CouldNotReadSnafu)
+            }
+        }
+    }
+
+    /// Send bytes as-is, without any framing. Mostly useful for poking at the protocol
+    pub fn send_raw(&mut self, bytes: &[u8]) -> Result<(), D30Error> {
+        self.write(bytes)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), D30Error> {
+        let transport = match &mut self.transport {
+            Some(transport) => transport,
+            None => return NotConnectedSnafu { state: self.state }.fail(),
+        };
+        let result = transport
+            .write_all(bytes)
+            .and_then(|_| transport.flush())
+            .context(CouldNotWriteSnafu);
+        if result.is_err() {
+            self.close(PrinterState::Error);
+            return result;
+        }
+        if let Some(capture) = &mut self.capture {
+            capture.record(Direction::Sent, bytes)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::COLOR_BLACK;
+
+    /// Where the single ink pixel of a label is, as read
+    fn ink_at(label: &DynamicImage) -> Option<(u32, u32)> {
+        let label = label.rotate90().to_rgb8();
+        label
+            .enumerate_pixels()
+            .find(|(_, _, pixel)| **pixel == COLOR_BLACK)
+            .map(|(x, y, _)| (x, y))
+    }
+
+    #[test]
+    fn offset_shifts_by_dots() {
+        // 12x40 on the D30, in reading orientation, with a dot at (100, 40)
+        let mut content = RgbImage::new(320, 96);
+        content.put_pixel(100, 40, COLOR_BLACK);
+        let label = DynamicImage::from(content).rotate270();
+
+        let mut printer = D30Printer::new(MacAddr6::nil(), RetryPolicy::default());
+        assert_eq!(ink_at(&printer.shift(&label)), Some((100, 40)));
+        // 1mm is 8 dots at 203 dpi
+        printer.set_offset(1.0, 0.0);
+        assert_eq!(ink_at(&printer.shift(&label)), Some((108, 40)));
+        printer.set_offset(-1.0, 0.5);
+        assert_eq!(ink_at(&printer.shift(&label)), Some((92, 44)));
+        // Shifted off the label
+        printer.set_offset(0.0, 10.0);
+        assert_eq!(ink_at(&printer.shift(&label)), None);
+    }
+}
@@ -0,0 +1,312 @@
+//! Best-effort decoding of the byte streams exchanged with Phomemo printers.
+//!
+//! Most of the protocol was sniffed rather than documented, so apart from the
+//! ESC/POS commands, descriptions here are educated guesses. Anything not in
+//! the tables below is reported as unknown, so new commands stand out.
+
+use std::fmt;
+
+/// `1f 11 <code> [args]` commands: code, number of argument bytes, description
+const COMMANDS_1F11: &[(u8, usize, &str)] = &[
+    (0x02, 1, "set print density?"),
+    (0x07, 0, "query (unknown)"),
+    (0x08, 0, "query battery level?"),
+    (0x09, 0, "query (unknown)"),
+    (0x0a, 0, "media type: labels with gaps"),
+    (0x0b, 0, "media type: continuous"),
+    (0x11, 0, "query (unknown)"),
+    (0x12, 0, "query firmware version?"),
+    (0x13, 0, "query serial number?"),
+    (0x19, 0, "query (unknown)"),
+    (0x24, 1, "start of print job?"),
+    (0x26, 0, "media type: black mark"),
+    (0x38, 0, "query (unknown)"),
+];
+
+/// `1a <code> <value>` status reports from the printer
+const RESPONSES_1A: &[(u8, &str)] = &[
+    (0x04, "battery level?"),
+    (0x06, "paper state?"),
+    (0x0f, "print finished?"),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandKind {
+    /// A `1f 11` command from the table, with its description
+    Known(&'static str),
+    /// A `1f 11` command we don't have a description for yet
+    Unknown1f11 { code: u8 },
+    /// ESC @
+    Reset,
+    /// GS v 0: a raster bit image of `width_bytes * 8` by `height` dots follows
+    Raster { width_bytes: u16, height: u16 },
+    /// Bitmap data belonging to the last raster header
+    RasterData,
+    /// A `1a` status report from the printer
+    Response {
+        code: u8,
+        description: Option<&'static str>,
+    },
+    /// Bytes that don't look like any command we know
+    Unrecognized,
+}
+
+/// A single command, or a run of bytes that belong together, found in a stream
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    pub bytes: Vec<u8>,
+    pub kind: CommandKind,
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let shown = if self.bytes.len() > 16 {
+            format!(
+                "{}… ({} bytes)",
+                hex::encode(&self.bytes[..16]),
+                self.bytes.len()
+            )
+        } else {
+            hex::encode(&self.bytes)
+        };
+        match &self.kind {
+            CommandKind::Known(description) => write!(f, "{}  {}", shown, description),
+            CommandKind::Unknown1f11 { code } => {
+                write!(f, "{}  UNKNOWN command 1f11{:02x}", shown, code)
+            }
+            CommandKind::Reset => write!(f, "{}  ESC @ (reset)", shown),
+            CommandKind::Raster {
+                width_bytes,
+                height,
+            } => write!(
+                f,
+                "{}  raster image, {}x{} dots",
+                shown,
+                *width_bytes as u32 * 8,
+                height
+            ),
+            CommandKind::RasterData => write!(f, "{}  raster data", shown),
+            CommandKind::Response { code, description } => write!(
+                f,
+                "{}  status 1a{:02x}: {}",
+                shown,
+                code,
+                description.unwrap_or("UNKNOWN")
+            ),
+            CommandKind::Unrecognized => write!(f, "{}  ???", shown),
+        }
+    }
+}
+
+/// Splits a stream into commands. Keeps state between calls to `feed`,
+/// since raster data is usually spread over several writes.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    raster_remaining: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn feed(&mut self, mut bytes: &[u8]) -> Vec<Decoded> {
+        let mut decoded: Vec<Decoded> = Vec::new();
+        while !bytes.is_empty() {
+            let (len, kind) = self.next_command(bytes);
+            let len = len.min(bytes.len());
+            // Merge runs of unrecognized bytes rather than reporting them one by one
+            match decoded.last_mut() {
+                Some(last)
+                    if last.kind == CommandKind::Unrecognized
+                        && kind == CommandKind::Unrecognized =>
+                {
+                    last.bytes.extend_from_slice(&bytes[..len])
+                }
+                _ => decoded.push(Decoded {
+                    bytes: bytes[..len].to_vec(),
+                    kind,
+                }),
+            }
+            bytes = &bytes[len..];
+        }
+        decoded
+    }
+
+    fn next_command(&mut self, bytes: &[u8]) -> (usize, CommandKind) {
+        if self.raster_remaining > 0 {
+            let len = self.raster_remaining.min(bytes.len());
+            self.raster_remaining -= len;
+            return (len, CommandKind::RasterData);
+        }
+        match bytes {
+            [0x1f, 0x11, code, ..] => match COMMANDS_1F11.iter().find(|(c, ..)| c == code) {
+                Some((_, args, description)) => (3 + args, CommandKind::Known(description)),
+                None => (3, CommandKind::Unknown1f11 { code: *code }),
+            },
+            [0x1b, 0x40, ..] => (2, CommandKind::Reset),
+            [0x1d, 0x76, 0x30, _mode, x_l, x_h, y_l, y_h, ..] => {
+                let width_bytes = u16::from_le_bytes([*x_l, *x_h]);
+                let height = u16::from_le_bytes([*y_l, *y_h]);
+                self.raster_remaining = width_bytes as usize * height as usize;
+                (
+                    8,
+                    CommandKind::Raster {
+                        width_bytes,
+                        height,
+                    },
+                )
+            }
+            [0x1a, code, ..] => (
+                3,
+                CommandKind::Response {
+                    code: *code,
+                    description: RESPONSES_1A
+                        .iter()
+                        .find(|(c, _)| c == code)
+                        .map(|(_, description)| *description),
+                },
+            ),
+            _ => (1, CommandKind::Unrecognized),
+        }
+    }
+}
+
+/// Decode a self-contained buffer, e.g. a single command typed by hand
+pub fn decode(bytes: &[u8]) -> Vec<Decoded> {
+    Decoder::new().feed(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(decoded: &[Decoded]) -> Vec<(Vec<u8>, CommandKind)> {
+        decoded
+            .iter()
+            .map(|d| (d.bytes.clone(), d.kind.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn splits_a_print_job_into_commands() {
+        let job = [
+            0x1b, 0x40, // reset
+            0x1f, 0x11, 0x24, 0x00, // start of print job, one argument
+            0x1d, 0x76, 0x30, 0x00, 0x02, 0x00, 0x02, 0x00, // 16x2 raster
+            0xff, 0x00, 0x0f, 0xf0, // raster data
+            0x1f, 0x11, 0x08, // battery query, no arguments
+        ];
+        assert_eq!(
+            kinds(&decode(&job)),
+            vec![
+                (vec![0x1b, 0x40], CommandKind::Reset),
+                (
+                    vec![0x1f, 0x11, 0x24, 0x00],
+                    CommandKind::Known("start of print job?")
+                ),
+                (
+                    vec![0x1d, 0x76, 0x30, 0x00, 0x02, 0x00, 0x02, 0x00],
+                    CommandKind::Raster {
+                        width_bytes: 2,
+                        height: 2
+                    }
+                ),
+                (vec![0xff, 0x00, 0x0f, 0xf0], CommandKind::RasterData),
+                (
+                    vec![0x1f, 0x11, 0x08],
+                    CommandKind::Known("query battery level?")
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn raster_data_spans_several_feeds() {
+        let mut decoder = Decoder::new();
+        let header = decoder.feed(&[0x1d, 0x76, 0x30, 0x00, 0x01, 0x00, 0x03, 0x00, 0xaa]);
+        assert_eq!(
+            kinds(&header),
+            vec![
+                (
+                    vec![0x1d, 0x76, 0x30, 0x00, 0x01, 0x00, 0x03, 0x00],
+                    CommandKind::Raster {
+                        width_bytes: 1,
+                        height: 3
+                    }
+                ),
+                (vec![0xaa], CommandKind::RasterData),
+            ]
+        );
+        // Two bytes of raster data are still due, even if they look like a command
+        assert_eq!(
+            kinds(&decoder.feed(&[0x1b])),
+            vec![(vec![0x1b], CommandKind::RasterData)]
+        );
+        assert_eq!(
+            kinds(&decoder.feed(&[0x40, 0x1b, 0x40])),
+            vec![
+                (vec![0x40], CommandKind::RasterData),
+                (vec![0x1b, 0x40], CommandKind::Reset),
+            ]
+        );
+    }
+
+    #[test]
+    fn truncated_commands_keep_what_is_there() {
+        // The argument of `1f 11 02` is missing
+        assert_eq!(
+            kinds(&decode(&[0x1f, 0x11, 0x02])),
+            vec![(
+                vec![0x1f, 0x11, 0x02],
+                CommandKind::Known("set print density?")
+            )]
+        );
+        // A raster header cut short isn't a raster header
+        assert_eq!(
+            kinds(&decode(&[0x1d, 0x76, 0x30, 0x00, 0x01])),
+            vec![(
+                vec![0x1d, 0x76, 0x30, 0x00, 0x01],
+                CommandKind::Unrecognized
+            )]
+        );
+        assert_eq!(
+            kinds(&decode(&[0x1a, 0x04])),
+            vec![(
+                vec![0x1a, 0x04],
+                CommandKind::Response {
+                    code: 0x04,
+                    description: Some("battery level?")
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn unknown_opcodes_are_reported() {
+        assert_eq!(
+            kinds(&decode(&[
+                0x1f, 0x11, 0x77, 0x1a, 0x99, 0x01, 0x01, 0x02, 0x03
+            ])),
+            vec![
+                (
+                    vec![0x1f, 0x11, 0x77],
+                    CommandKind::Unknown1f11 { code: 0x77 }
+                ),
+                (
+                    vec![0x1a, 0x99, 0x01],
+                    CommandKind::Response {
+                        code: 0x99,
+                        description: None
+                    }
+                ),
+                // Runs of unrecognized bytes are merged
+                (vec![0x01, 0x02, 0x03], CommandKind::Unrecognized),
+            ]
+        );
+        assert_eq!(
+            decode(&[0x1f, 0x11, 0x77]).remove(0).to_string(),
+            "1f1177  UNKNOWN command 1f1177"
+        );
+    }
+}
@@ -0,0 +1,101 @@
+//! SVG input, rasterized at the size it is going to be printed at.
+
+use image::{DynamicImage, Rgba, RgbaImage};
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{
+        fontdb::{Database, Family, Query},
+        Options, Tree,
+    },
+};
+use snafu::{OptionExt, ResultExt};
+
+use crate::{
+    fit::should_rotate, Canvas, D30Error, EmptySvgSnafu, FitMode, FitOptions, InvalidSvgSnafu,
+    ModelSpec,
+};
+
+/// SVG lengths are in CSS pixels, at 96 per inch
+const SVG_DPI: f32 = 96.0;
+
+/// Rasterize an SVG or SVGZ file for `fit_image_with`, at exactly the size it will take up on the label,
+/// so it isn't scaled a second time. The `viewBox` and `preserveAspectRatio` of the file are respected.
+/// With `FitMode::Center`, the drawing is printed at its physical size.
+pub fn rasterize_svg(
+    data: &[u8],
+    spec: &ModelSpec,
+    canvas: Canvas,
+    options: &FitOptions,
+) -> Result<DynamicImage, D30Error> {
+    let mut svg_options = Options::default();
+    let fontdb = svg_options.fontdb_mut();
+    fontdb.load_system_fonts();
+    // So text still renders on systems without the usual font families
+    fontdb.load_font_data(Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]));
+    let missing = |fontdb: &Database, family| {
+        let query = Query {
+            families: &[family],
+            ..Default::default()
+        };
+        fontdb.query(&query).is_none()
+    };
+    if missing(fontdb, Family::SansSerif) {
+        fontdb.set_sans_serif_family("DejaVu Sans");
+    }
+    if missing(fontdb, Family::Serif) {
+        fontdb.set_serif_family("DejaVu Sans");
+    }
+    let tree = Tree::from_data(data, &svg_options).context(InvalidSvgSnafu)?;
+    let (width, height) = (tree.size().width(), tree.size().height());
+
+    let area = canvas.printable;
+    // The part of the area the drawing has to fill, in the drawing's own orientation
+    let rotate = should_rotate(width as u32, height as u32, canvas, options);
+    let (area_width, area_height) = if rotate {
+        (area.height() as f32, area.width() as f32)
+    } else {
+        (area.width() as f32, area.height() as f32)
+    };
+    let (scale_x, scale_y) = match options.mode {
+        FitMode::Center => {
+            let scale = spec.dpi as f32 / SVG_DPI;
+            (scale, scale)
+        }
+        // Only the tape width is fixed, which is the height of the area when the drawing isn't turned
+        _ if canvas.auto_length => {
+            let scale = if rotate {
+                area_width / width
+            } else {
+                area_height / height
+            };
+            (scale, scale)
+        }
+        FitMode::Contain => {
+            let scale = (area_width / width).min(area_height / height);
+            (scale, scale)
+        }
+        FitMode::Cover => {
+            let scale = (area_width / width).max(area_height / height);
+            (scale, scale)
+        }
+        FitMode::Stretch => (area_width / width, area_height / height),
+    };
+
+    let mut pixmap = Pixmap::new(
+        (width * scale_x).round().max(1.0) as u32,
+        (height * scale_y).round().max(1.0) as u32,
+    )
+    .context(EmptySvgSnafu)?;
+    resvg::render(
+        &tree,
+        Transform::from_scale(scale_x, scale_y),
+        &mut pixmap.as_mut(),
+    );
+    // Transparency is kept, and composited later like it is for any other image
+    let mut image = RgbaImage::new(pixmap.width(), pixmap.height());
+    for (pixel, color) in image.pixels_mut().zip(pixmap.pixels()) {
+        let color = color.demultiply();
+        *pixel = Rgba([color.red(), color.green(), color.blue(), color.alpha()]);
+    }
+    Ok(DynamicImage::from(image))
+}
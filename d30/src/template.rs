@@ -0,0 +1,535 @@
+//! Label templates: elements at fixed positions, with `{{placeholder}}` fields filled in at print time.
+//!
+//! Templates are TOML or JSON. Positions and sizes are measured from the top left corner of the label,
+//! in reading orientation. They are in mm, unless given as a string with a unit like `"8pt"` or `"40px"`.
+//! For example:
+//!
+//! ```toml
+//! media = "12x40"
+//!
+//! [[elements]]
+//! type = "text"
+//! x = 2.0
+//! y = 1.0
+//! size = 12.0
+//! text = "{{name}}"
+//!
+//! [[elements]]
+//! type = "barcode"
+//! symbology = "qr"
+//! x = 30.0
+//! y = 1.0
+//! width = 10.0
+//! height = 10.0
+//! data = "https://example.com/{{id}}"
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use image::{imageops::FilterType, DynamicImage, GrayImage, Luma, Rgb, RgbImage};
+use imageproc::{
+    drawing::{draw_filled_rect_mut, draw_line_segment_mut, draw_polygon_mut, draw_text_mut},
+    point::Point,
+    rect::Rect,
+};
+use rusttype::{Font, Scale};
+use serde::{Deserialize, Serialize};
+use snafu::{OptionExt, ResultExt};
+
+pub use crate::barcode::Symbology;
+use crate::{
+    barcode, to_gray, Canvas, CouldNotInitFontSnafu, CouldNotLoadTemplateFontSnafu,
+    CouldNotLoadTemplateImageSnafu, CouldNotReadTemplateSnafu, D30Error, Grayscale,
+    InvalidJsonTemplateSnafu, InvalidTemplateFontSnafu, InvalidTomlTemplateSnafu, Length, Margins,
+    MissingPlaceholderSnafu, ModelSpec, Unit, COLOR_BLACK,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    /// Media the template was designed for, e.g. `12x40`. Can be overridden when printing
+    pub media: Option<String>,
+    #[serde(default)]
+    pub elements: Vec<Element>,
+}
+
+fn default_size() -> Length {
+    Length {
+        value: 10.0,
+        unit: Some(Unit::Pt),
+    }
+}
+
+fn default_thickness() -> Length {
+    Length::mm(0.3)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Element {
+    /// A single line of text. `y` is the top of the line
+    Text {
+        x: Length,
+        y: Length,
+        text: String,
+        /// Font size, in pt unless given with a unit
+        #[serde(default = "default_size")]
+        size: Length,
+        /// TrueType font file. Defaults to the built in DejaVu Sans
+        font: Option<PathBuf>,
+    },
+    /// An image file, scaled to fit `width` and/or `height` if given. Dark pixels become ink
+    Image {
+        x: Length,
+        y: Length,
+        path: String,
+        width: Option<Length>,
+        height: Option<Length>,
+    },
+    /// No quiet zone is added, so leave some blank space around it
+    Barcode {
+        x: Length,
+        y: Length,
+        data: String,
+        #[serde(default)]
+        symbology: Symbology,
+        width: Length,
+        height: Length,
+    },
+    Line {
+        x1: Length,
+        y1: Length,
+        x2: Length,
+        y2: Length,
+        #[serde(default = "default_thickness")]
+        thickness: Length,
+    },
+    #[serde(rename = "box")]
+    Rect {
+        x: Length,
+        y: Length,
+        width: Length,
+        height: Length,
+        #[serde(default = "default_thickness")]
+        thickness: Length,
+        #[serde(default)]
+        filled: bool,
+    },
+}
+
+/// An element with placeholders filled in, resources loaded and sizes in dots
+enum Drawable {
+    Text {
+        x: i32,
+        y: i32,
+        scale: Scale,
+        font: Font<'static>,
+        text: String,
+    },
+    Bitmap {
+        x: i64,
+        y: i64,
+        image: GrayImage,
+    },
+    Line {
+        from: (f32, f32),
+        to: (f32, f32),
+        thickness: f32,
+    },
+    Rect {
+        rect: Rect,
+        thickness: u32,
+        filled: bool,
+    },
+}
+
+impl Drawable {
+    /// First dot past the right end of the element
+    fn right(&self) -> i64 {
+        match self {
+            Drawable::Text {
+                x,
+                scale,
+                font,
+                text,
+                ..
+            } => {
+                let (width, _) = imageproc::drawing::text_size(*scale, font, text);
+                (*x + width) as i64
+            }
+            Drawable::Bitmap { x, image, .. } => x + image.width() as i64,
+            Drawable::Line {
+                from,
+                to,
+                thickness,
+            } => (from.0.max(to.0) + thickness / 2.0).ceil() as i64 + 1,
+            Drawable::Rect { rect, .. } => rect.right() as i64 + 1,
+        }
+    }
+
+    fn draw(&self, canvas: &mut RgbImage) {
+        match self {
+            Drawable::Text {
+                x,
+                y,
+                scale,
+                font,
+                text,
+            } => draw_text_mut(canvas, COLOR_BLACK, *x, *y, *scale, font, text),
+            Drawable::Bitmap { x, y, image } => {
+                // Only add ink, so a bitmap's blank background doesn't erase what's underneath
+                for (dx, dy, Luma([ink])) in image.enumerate_pixels() {
+                    let (px, py) = (x + dx as i64, y + dy as i64);
+                    if *ink == 0
+                        || px < 0
+                        || py < 0
+                        || px >= canvas.width() as i64
+                        || py >= canvas.height() as i64
+                    {
+                        continue;
+                    }
+                    let pixel = canvas.get_pixel_mut(px as u32, py as u32);
+                    pixel.0 = pixel.0.map(|channel| channel.max(*ink));
+                }
+            }
+            Drawable::Line {
+                from,
+                to,
+                thickness,
+            } => {
+                let (dx, dy) = (to.0 - from.0, to.1 - from.1);
+                let length = (dx * dx + dy * dy).sqrt();
+                if *thickness <= 1.0 || length == 0.0 {
+                    draw_line_segment_mut(canvas, *from, *to, COLOR_BLACK);
+                    return;
+                }
+                // Offset both ends by half the thickness, perpendicular to the line
+                let (nx, ny) = (
+                    -dy / length * thickness / 2.0,
+                    dx / length * thickness / 2.0,
+                );
+                let corners = [
+                    (from.0 + nx, from.1 + ny),
+                    (to.0 + nx, to.1 + ny),
+                    (to.0 - nx, to.1 - ny),
+                    (from.0 - nx, from.1 - ny),
+                ]
+                .map(|(x, y)| Point::new(x.round() as i32, y.round() as i32));
+                if corners[0] == corners[3] || corners[1] == corners[2] {
+                    draw_line_segment_mut(canvas, *from, *to, COLOR_BLACK);
+                } else {
+                    draw_polygon_mut(canvas, &corners, COLOR_BLACK);
+                }
+            }
+            Drawable::Rect {
+                rect,
+                thickness,
+                filled,
+            } => {
+                let thickness = *thickness;
+                if *filled || thickness * 2 >= rect.width().min(rect.height()) {
+                    draw_filled_rect_mut(canvas, *rect, COLOR_BLACK);
+                    return;
+                }
+                let (left, top) = (rect.left(), rect.top());
+                let (width, height) = (rect.width(), rect.height());
+                for side in [
+                    Rect::at(left, top).of_size(width, thickness),
+                    Rect::at(left, rect.bottom() + 1 - thickness as i32).of_size(width, thickness),
+                    Rect::at(left, top).of_size(thickness, height),
+                    Rect::at(rect.right() + 1 - thickness as i32, top).of_size(thickness, height),
+                ] {
+                    draw_filled_rect_mut(canvas, side, COLOR_BLACK);
+                }
+            }
+        }
+    }
+}
+
+/// Replace every `{{name}}` in `text` with its value. Unterminated `{{` are left as they are
+fn fill_placeholders(text: &str, values: &HashMap<String, String>) -> Result<String, D30Error> {
+    let mut output = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        let name = rest[start + 2..start + end].trim();
+        let value = values.get(name).context(MissingPlaceholderSnafu { name })?;
+        output.push_str(&rest[..start]);
+        output.push_str(value);
+        rest = &rest[start + end + 2..];
+    }
+    output.push_str(rest);
+    Ok(output)
+}
+
+impl Template {
+    /// Read a template, as JSON if the file ends in `.json` and as TOML otherwise
+    pub fn load(path: &Path) -> Result<Self, D30Error> {
+        let contents = fs::read_to_string(path).context(CouldNotReadTemplateSnafu { path })?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("json") => {
+                serde_json::from_str(&contents).context(InvalidJsonTemplateSnafu { path })
+            }
+            _ => toml::from_str(&contents).context(InvalidTomlTemplateSnafu { path }),
+        }
+    }
+
+    /// Names of all placeholders used in the template, in order of appearance
+    pub fn placeholders(&self) -> Vec<String> {
+        let mut names: Vec<String> = Vec::new();
+        for element in &self.elements {
+            let fields = match element {
+                Element::Text { text, .. } => text,
+                Element::Image { path, .. } => path,
+                Element::Barcode { data, .. } => data,
+                _ => continue,
+            };
+            for part in fields.split("{{").skip(1) {
+                if let Some((name, _)) = part.split_once("}}") {
+                    let name = name.trim().to_string();
+                    if !names.contains(&name) {
+                        names.push(name);
+                    }
+                }
+            }
+        }
+        names
+    }
+
+    /// Render the template onto a label canvas from `ModelSpec::canvas`.
+    /// Relative image and font paths are resolved against `base_dir`.
+    /// On auto length canvases, the label is as long as the elements need.
+    pub fn render(
+        &self,
+        values: &HashMap<String, String>,
+        spec: &ModelSpec,
+        canvas: Canvas,
+        base_dir: &Path,
+    ) -> Result<DynamicImage, D30Error> {
+        let drawables = self
+            .elements
+            .iter()
+            .map(|element| element.prepare(values, spec, base_dir))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let canvas = if canvas.auto_length {
+            let right = drawables.iter().map(Drawable::right).max().unwrap_or(1);
+            let length = right - canvas.printable.left() as i64;
+            canvas.fit_length(&Margins::default(), length.clamp(1, u32::MAX as i64) as u32)
+        } else {
+            canvas
+        };
+        let mut output = RgbImage::new(canvas.width, canvas.height);
+        for drawable in &drawables {
+            drawable.draw(&mut output);
+        }
+        Ok(DynamicImage::from(output).rotate270())
+    }
+}
+
+impl Element {
+    fn prepare(
+        &self,
+        values: &HashMap<String, String>,
+        spec: &ModelSpec,
+        base_dir: &Path,
+    ) -> Result<Drawable, D30Error> {
+        let exact = |length: &Length| length.dots(spec.dpi, Unit::Mm);
+        let dots = |length: &Length| exact(length).round().max(0.0) as u32;
+        let point = |x: &Length, y: &Length| (exact(x), exact(y));
+        Ok(match self {
+            Element::Text {
+                x,
+                y,
+                text,
+                size,
+                font,
+            } => {
+                let font = match font {
+                    Some(path) => {
+                        let path = base_dir.join(path);
+                        let bytes = fs::read(&path)
+                            .context(CouldNotLoadTemplateFontSnafu { path: &path })?;
+                        Font::try_from_vec(bytes).context(InvalidTemplateFontSnafu { path })?
+                    }
+                    None => {
+                        Font::try_from_vec(Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]))
+                            .context(CouldNotInitFontSnafu)?
+                    }
+                };
+                Drawable::Text {
+                    x: exact(x).round() as i32,
+                    y: exact(y).round() as i32,
+                    scale: Scale::uniform(size.dots(spec.dpi, Unit::Pt)),
+                    font,
+                    text: fill_placeholders(text, values)?,
+                }
+            }
+            Element::Image {
+                x,
+                y,
+                path,
+                width,
+                height,
+            } => {
+                let path = base_dir.join(fill_placeholders(path, values)?);
+                let image = image::open(&path).context(CouldNotLoadTemplateImageSnafu { path })?;
+                let mut image = DynamicImage::ImageLuma8(to_gray(
+                    &image,
+                    Rgb([255, 255, 255]),
+                    Grayscale::Luma,
+                ));
+                image.invert();
+                let image = match (width, height) {
+                    (None, None) => image,
+                    (Some(width), None) => {
+                        image.resize(dots(width), u32::MAX, FilterType::Triangle)
+                    }
+                    (None, Some(height)) => {
+                        image.resize(u32::MAX, dots(height), FilterType::Triangle)
+                    }
+                    (Some(width), Some(height)) => {
+                        image.resize(dots(width), dots(height), FilterType::Triangle)
+                    }
+                };
+                Drawable::Bitmap {
+                    x: exact(x).round() as i64,
+                    y: exact(y).round() as i64,
+                    image: image.to_luma8(),
+                }
+            }
+            Element::Barcode {
+                x,
+                y,
+                data,
+                symbology,
+                width,
+                height,
+            } => Drawable::Bitmap {
+                x: exact(x).round() as i64,
+                y: exact(y).round() as i64,
+                image: barcode::render(
+                    *symbology,
+                    &fill_placeholders(data, values)?,
+                    dots(width),
+                    dots(height),
+                )?,
+            },
+            Element::Line {
+                x1,
+                y1,
+                x2,
+                y2,
+                thickness,
+            } => Drawable::Line {
+                from: point(x1, y1),
+                to: point(x2, y2),
+                thickness: exact(thickness),
+            },
+            Element::Rect {
+                x,
+                y,
+                width,
+                height,
+                thickness,
+                filled,
+            } => Drawable::Rect {
+                rect: Rect::at(exact(x).round() as i32, exact(y).round() as i32)
+                    .of_size(dots(width).max(1), dots(height).max(1)),
+                thickness: dots(thickness).max(1),
+                filled: *filled,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{D30Error, Media, Model};
+
+    const TEMPLATE: &str = r#"
+        media = "12x40"
+
+        [[elements]]
+        type = "text"
+        x = "120px"
+        y = "8px"
+        text = "Hi {{name}}"
+
+        [[elements]]
+        type = "box"
+        x = "16px"
+        y = "8px"
+        width = "40px"
+        height = "24px"
+        thickness = "2px"
+    "#;
+
+    fn values(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    /// Render in reading orientation, like the canvas is laid out
+    fn render(template: &Template, values: &HashMap<String, String>) -> Result<RgbImage, D30Error> {
+        let spec = Model::D30.spec();
+        let canvas = spec.canvas(&Media::new(12.0, 40.0));
+        let image = template.render(values, spec, canvas, Path::new("."))?;
+        Ok(image.rotate90().to_rgb8())
+    }
+
+    #[test]
+    fn box_lands_on_exact_pixels() {
+        let template: Template = toml::from_str(TEMPLATE).unwrap();
+        assert_eq!(template.placeholders(), ["name"]);
+        let image = render(&template, &values(&[("name", "Ada")])).unwrap();
+        assert_eq!(image.dimensions(), (320, 96));
+        let ink = |x, y| image.get_pixel(x, y) == &COLOR_BLACK;
+        // Corners and edges of the 2px outline
+        for (x, y) in [
+            (16, 8),
+            (17, 9),
+            (55, 8),
+            (54, 9),
+            (16, 31),
+            (55, 31),
+            (35, 30),
+        ] {
+            assert!(ink(x, y), "no ink at {}, {}", x, y);
+        }
+        // Just outside, and just inside the outline
+        for (x, y) in [(15, 8), (16, 7), (56, 31), (16, 32), (18, 10), (53, 29)] {
+            assert!(!ink(x, y), "ink at {}, {}", x, y);
+        }
+        // The text went to the right of the box
+        assert!((0..96).any(|y| (120..320).any(|x| ink(x, y))));
+    }
+
+    #[test]
+    fn unknown_placeholder_fails() {
+        let template: Template = toml::from_str(TEMPLATE).unwrap();
+        let result = render(&template, &values(&[("nmae", "Ada")]));
+        assert!(
+            matches!(result, Err(D30Error::MissingPlaceholder { ref name }) if name == "name"),
+            "{:?}",
+            result.err()
+        );
+    }
+
+    #[test]
+    fn fill_placeholders_trims_names_and_leaves_unterminated_braces() {
+        let values = values(&[("id", "42")]);
+        assert_eq!(
+            fill_placeholders("#{{ id }} {{id", &values).unwrap(),
+            "#42 {{id"
+        );
+    }
+}
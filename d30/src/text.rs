@@ -0,0 +1,388 @@
+//! Laying out text in lines: explicit newlines, word wrapping and alignment.
+
+use std::str::FromStr;
+
+use derive_more::Display;
+use image::RgbImage;
+use imageproc::drawing::{draw_text_mut, text_size};
+use log::{trace, warn};
+use rusttype::{Font, Scale};
+use snafu::OptionExt;
+
+use crate::{
+    Canvas, CouldNotInitFontSnafu, D30Error, D30Scale, Length, Margins, Unit, COLOR_BLACK,
+};
+
+/// Steps of the search for the largest scale that fits, which gets within a hundredth of a pixel
+const SCALE_SEARCH_STEPS: u32 = 20;
+
+/// Horizontal alignment of the lines of a text block
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Align {
+    #[display(fmt = "left")]
+    Left,
+    #[default]
+    #[display(fmt = "center")]
+    Center,
+    #[display(fmt = "right")]
+    Right,
+}
+
+impl FromStr for Align {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "left" => Ok(Self::Left),
+            "center" => Ok(Self::Center),
+            "right" => Ok(Self::Right),
+            _ => Err(format!(
+                "Invalid alignment: {}. Expected `left`, `center` or `right`",
+                s
+            )),
+        }
+    }
+}
+
+/// Where the text block goes across the label
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, Default)]
+pub enum VerticalAlign {
+    #[display(fmt = "top")]
+    Top,
+    /// The block centered, descenders included
+    #[default]
+    #[display(fmt = "middle")]
+    Middle,
+    #[display(fmt = "bottom")]
+    Bottom,
+    /// Centered from the top of the first line to the baseline of the last, ignoring descenders,
+    /// which looks right for capitals and numbers
+    #[display(fmt = "baseline")]
+    Baseline,
+}
+
+impl FromStr for VerticalAlign {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "top" => Ok(Self::Top),
+            "middle" | "center" => Ok(Self::Middle),
+            "bottom" => Ok(Self::Bottom),
+            "baseline" => Ok(Self::Baseline),
+            _ => Err(format!(
+                "Invalid vertical alignment: {}. Expected `top`, `middle`, `bottom` or `baseline`",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TextLayout {
+    pub align: Align,
+    pub vertical_align: VerticalAlign,
+    /// Put the baseline of the first line this far below the top of the content area, in pixels
+    /// unless given in another unit, instead of aligning the block. Keeps text on the same line
+    /// across a series of labels
+    pub baseline: Option<Length>,
+    /// Break lines between words so they fit the label. Has no effect on auto length canvases
+    pub wrap: bool,
+    /// Distance between lines, as a multiple of the font's own line height
+    pub line_spacing: f32,
+}
+
+impl Default for TextLayout {
+    fn default() -> Self {
+        Self {
+            align: Align::default(),
+            vertical_align: VerticalAlign::default(),
+            baseline: None,
+            wrap: false,
+            line_spacing: 1.0,
+        }
+    }
+}
+
+/// Lines of a text block at one scale, with the size they take up
+struct Block {
+    lines: Vec<String>,
+    advance: f32,
+    /// Of a single line, measured from its baseline. `descent` is negative
+    ascent: f32,
+    descent: f32,
+    width: i32,
+    height: f32,
+}
+
+impl Block {
+    fn new(
+        font: &Font,
+        scale: f32,
+        text: &str,
+        layout: &TextLayout,
+        wrap_width: Option<i32>,
+    ) -> Self {
+        let lines: Vec<String> = text
+            .lines()
+            .flat_map(|line| match wrap_width {
+                Some(width) => wrap(font, scale, line, width),
+                None => vec![line.to_owned()],
+            })
+            .collect();
+        let metrics = font.v_metrics(Scale::uniform(scale));
+        let advance = (metrics.ascent - metrics.descent + metrics.line_gap) * layout.line_spacing;
+        let sizes: Vec<(i32, i32)> = lines
+            .iter()
+            .map(|line| text_size(Scale::uniform(scale), font, line))
+            .collect();
+        let width = sizes.iter().map(|(width, _)| *width).max().unwrap_or(0);
+        // From the ascent of the first line to the descent of the last, so accents and descenders fit
+        let height =
+            advance * lines.len().saturating_sub(1) as f32 + metrics.ascent - metrics.descent;
+        Self {
+            lines,
+            advance,
+            ascent: metrics.ascent,
+            descent: metrics.descent,
+            width,
+            height,
+        }
+    }
+
+    /// Distance from the top of an area `area_height` high to the top of the block
+    fn offset(&self, layout: &TextLayout, dpi: u32, area_height: f32) -> f32 {
+        if let Some(baseline) = layout.baseline {
+            return baseline.dots(dpi, Unit::Px) - self.ascent;
+        }
+        match layout.vertical_align {
+            VerticalAlign::Top => 0.0,
+            VerticalAlign::Middle => (area_height - self.height) / 2.0,
+            VerticalAlign::Bottom => area_height - self.height,
+            VerticalAlign::Baseline => (area_height - (self.height + self.descent)) / 2.0,
+        }
+    }
+
+    /// Whether the block stays inside an area `area_height` high when aligned by `layout`
+    fn fits_height(&self, layout: &TextLayout, dpi: u32, area_height: f32) -> bool {
+        let offset = self.offset(layout, dpi, area_height);
+        offset >= 0.0 && offset + self.height <= area_height
+    }
+}
+
+/// Break a line between words, so each piece is at most `width` wide. Words that are too long by
+/// themselves get a line of their own
+fn wrap(font: &Font, scale: f32, line: &str, width: i32) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+    for word in line.split_whitespace() {
+        let candidate = if current.is_empty() {
+            word.to_owned()
+        } else {
+            format!("{} {}", current, word)
+        };
+        if current.is_empty() || text_size(Scale::uniform(scale), font, &candidate).0 <= width {
+            current = candidate;
+        } else {
+            lines.push(std::mem::replace(&mut current, word.to_owned()));
+        }
+    }
+    lines.push(current);
+    lines
+}
+
+/// The scale to render at. Auto scaling searches for the largest scale that `fits`,
+/// which is never more than `largest`, since a single line is as high as its scale
+pub(crate) fn fit_scale(
+    font_scale: D30Scale,
+    dpi: u32,
+    largest: f32,
+    fits: impl Fn(f32) -> bool,
+) -> f32 {
+    let dots = |length: Length| length.dots(dpi, Unit::Px);
+    let (min, max) = match font_scale {
+        D30Scale::Value(scale) => return dots(scale),
+        D30Scale::Auto { min, max } => (
+            min.map(dots).unwrap_or(1.0),
+            max.map(dots).unwrap_or(largest).max(1.0),
+        ),
+    };
+    if !fits(min) {
+        warn!(
+            "Text doesn't fit at the minimum scale of {}, and will be cut off",
+            min
+        );
+        return min;
+    }
+    if fits(max) {
+        return max;
+    }
+    let (mut low, mut high) = (min, max);
+    for _ in 0..SCALE_SEARCH_STEPS {
+        let middle = (low + high) / 2.0;
+        if fits(middle) {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+/// `generate_text_image`, in reading orientation
+pub(crate) fn render_text(
+    text: &str,
+    margins: &Margins,
+    font_scale: D30Scale,
+    canvas: Canvas,
+    layout: &TextLayout,
+) -> Result<RgbImage, D30Error> {
+    let area = canvas.content_area(margins);
+    trace!("{:#?}", &area);
+    let font = Vec::from(include_bytes!("DejaVuSans.ttf") as &[u8]);
+    let font = Font::try_from_vec(font).context(CouldNotInitFontSnafu)?;
+    let wrap_width = (layout.wrap && !canvas.auto_length).then_some(area.width() as i32);
+
+    let scale = fit_scale(font_scale, canvas.dpi, area.height() as f32, |scale| {
+        let block = Block::new(&font, scale, text, layout, wrap_width);
+        // Without a fixed length, only the tape width limits the text
+        (canvas.auto_length || block.width <= area.width() as i32)
+            && block.fits_height(layout, canvas.dpi, area.height() as f32)
+    });
+    let block = Block::new(&font, scale, text, layout, wrap_width);
+    let canvas = if canvas.auto_length {
+        canvas.fit_length(margins, block.width.max(1) as u32)
+    } else {
+        canvas
+    };
+    let area = canvas.content_area(margins);
+    let top = area.top()
+        + block
+            .offset(layout, canvas.dpi, area.height() as f32)
+            .round() as i32;
+
+    let mut output = RgbImage::new(canvas.width, canvas.height);
+    for (i, line) in block.lines.iter().enumerate() {
+        let (width, _) = text_size(Scale::uniform(scale), &font, line);
+        let x = match layout.align {
+            Align::Left => area.left(),
+            Align::Center => area.left() + (area.width() as i32 - width) / 2,
+            Align::Right => area.left() + area.width() as i32 - width,
+        };
+        draw_text_mut(
+            &mut output,
+            COLOR_BLACK,
+            x,
+            top + (block.advance * i as f32) as i32,
+            Scale::uniform(scale),
+            &font,
+            line,
+        );
+    }
+
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const AUTO: D30Scale = D30Scale::Auto {
+        min: None,
+        max: None,
+    };
+
+    fn font() -> Font<'static> {
+        Font::try_from_bytes(include_bytes!("DejaVuSans.ttf")).unwrap()
+    }
+
+    fn canvas() -> Canvas {
+        Canvas::new(320, 96, 203)
+    }
+
+    fn margins() -> Margins {
+        Margins::uniform(Length::px(8.0))
+    }
+
+    /// First and last row with any ink, among the columns in `columns`
+    fn ink_rows(image: &RgbImage, columns: std::ops::Range<u32>) -> (u32, u32) {
+        let rows: Vec<u32> = (0..image.height())
+            .filter(|&y| columns.clone().any(|x| image.get_pixel(x, y)[0] > 0))
+            .collect();
+        (rows[0], rows[rows.len() - 1])
+    }
+
+    #[test]
+    fn auto_scale_keeps_descenders_inside() {
+        let area = canvas().content_area(&margins());
+        let layout = TextLayout::default();
+        for text in ["gjpqy", "ABC"] {
+            let image = render_text(text, &margins(), AUTO, canvas(), &layout).unwrap();
+            let (top, bottom) = ink_rows(&image, 0..image.width());
+            assert!(top >= area.top() as u32, "{} starts at row {}", text, top);
+            assert!(
+                bottom <= area.bottom() as u32,
+                "{} ends at row {}",
+                text,
+                bottom
+            );
+            // Sized for the area, not just anywhere inside it
+            assert!(
+                bottom - top > area.height() / 2,
+                "{} is {} rows high",
+                text,
+                bottom - top
+            );
+        }
+    }
+
+    #[test]
+    fn wrap_puts_long_words_on_their_own_line() {
+        let font = font();
+        let width = text_size(Scale::uniform(20.0), &font, "a bc").0;
+        assert_eq!(
+            wrap(&font, 20.0, "a bc supercalifragilistic de f", width),
+            ["a bc", "supercalifragilistic", "de f"]
+        );
+    }
+
+    #[test]
+    fn wrap_keeps_empty_lines() {
+        let font = font();
+        assert_eq!(wrap(&font, 20.0, "", 100), [""]);
+        let layout = TextLayout::default();
+        let block = Block::new(&font, 20.0, "one\n\ntwo", &layout, Some(100));
+        assert_eq!(block.lines, ["one", "", "two"]);
+    }
+
+    #[test]
+    fn baseline_ignores_descenders() {
+        let scale = D30Scale::Value(Length::px(40.0));
+        for layout in [
+            TextLayout {
+                vertical_align: VerticalAlign::Baseline,
+                ..TextLayout::default()
+            },
+            TextLayout {
+                baseline: Some(Length::px(50.0)),
+                ..TextLayout::default()
+            },
+        ] {
+            let layout = TextLayout {
+                align: Align::Left,
+                ..layout
+            };
+            let left = canvas().content_area(&margins()).left() as u32;
+            // The `A` is drawn the same in both, so its bottom is the baseline
+            let baseline = |text| {
+                let image = render_text(text, &margins(), scale, canvas(), &layout).unwrap();
+                ink_rows(&image, left..left + 10).1
+            };
+            assert_eq!(baseline("ABC"), baseline("Agjpqy"), "{:?}", layout);
+            if layout.baseline.is_some() {
+                let area = canvas().content_area(&margins());
+                assert!(baseline("ABC").abs_diff(area.top() as u32 + 50) <= 1);
+            }
+        }
+    }
+}
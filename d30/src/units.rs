@@ -0,0 +1,314 @@
+//! Lengths in physical units, converted to dots at the printer's resolution.
+
+use std::{fmt, str::FromStr};
+
+use derive_more::Display;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq)]
+pub enum Unit {
+    /// Printer dots
+    #[display(fmt = "px")]
+    Px,
+    #[display(fmt = "mm")]
+    Mm,
+    /// 1/72 inch
+    #[display(fmt = "pt")]
+    Pt,
+}
+
+/// A length like `2mm`, `12pt` or `15px`. Bare numbers have no unit, and are taken in the
+/// unit that is the default where the length is used
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "LengthValue", into = "LengthValue")]
+pub struct Length {
+    pub value: f32,
+    pub unit: Option<Unit>,
+}
+
+impl Length {
+    pub const fn px(value: f32) -> Self {
+        Self {
+            value,
+            unit: Some(Unit::Px),
+        }
+    }
+
+    pub const fn mm(value: f32) -> Self {
+        Self {
+            value,
+            unit: Some(Unit::Mm),
+        }
+    }
+
+    /// The length in dots at `dpi`, reading bare numbers as `default`
+    pub fn dots(&self, dpi: u32, default: Unit) -> f32 {
+        match self.unit.unwrap_or(default) {
+            Unit::Px => self.value,
+            Unit::Mm => self.value * dpi as f32 / 25.4,
+            Unit::Pt => self.value * dpi as f32 / 72.0,
+        }
+    }
+}
+
+impl FromStr for Length {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (number, unit) = [("mm", Unit::Mm), ("pt", Unit::Pt), ("px", Unit::Px)]
+            .into_iter()
+            .find_map(|(suffix, unit)| Some((s.strip_suffix(suffix)?, Some(unit))))
+            .unwrap_or((s, None));
+        number
+            .trim()
+            .parse::<f32>()
+            .map(|value| Self { value, unit })
+            .map_err(|_| {
+                format!(
+                    "Invalid length: {}. Expected a number, optionally followed by `mm`, `pt` or `px`",
+                    s
+                )
+            })
+    }
+}
+
+impl fmt::Display for Length {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.unit {
+            Some(unit) => write!(f, "{}{}", self.value, unit),
+            None => write!(f, "{}", self.value),
+        }
+    }
+}
+
+/// Lengths in config and template files are either a bare number or a string with a unit
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum LengthValue {
+    Number(f32),
+    Text(String),
+}
+
+impl TryFrom<LengthValue> for Length {
+    type Error = String;
+
+    fn try_from(value: LengthValue) -> Result<Self, Self::Error> {
+        match value {
+            LengthValue::Number(value) => Ok(Self { value, unit: None }),
+            LengthValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<Length> for LengthValue {
+    fn from(length: Length) -> Self {
+        match length.unit {
+            Some(_) => LengthValue::Text(length.to_string()),
+            None => LengthValue::Number(length.value),
+        }
+    }
+}
+
+/// Blank space kept around the content, in reading orientation
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(try_from = "LengthValue", into = "LengthValue")]
+pub struct Margins {
+    pub top: Length,
+    pub right: Length,
+    pub bottom: Length,
+    pub left: Length,
+}
+
+impl Margins {
+    pub const fn uniform(length: Length) -> Self {
+        Self {
+            top: length,
+            right: length,
+            bottom: length,
+            left: length,
+        }
+    }
+}
+
+/// One to four lengths, separated by spaces or commas, in the same order as CSS:
+/// all sides, `<top and bottom> <left and right>`, `<top> <left and right> <bottom>`,
+/// or `<top> <right> <bottom> <left>`
+impl FromStr for Margins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let lengths = s
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|part| !part.is_empty())
+            .map(str::parse)
+            .collect::<Result<Vec<Length>, _>>()?;
+        match lengths[..] {
+            [all] => Ok(Self::uniform(all)),
+            [vertical, horizontal] => Ok(Self {
+                top: vertical,
+                right: horizontal,
+                bottom: vertical,
+                left: horizontal,
+            }),
+            [top, horizontal, bottom] => Ok(Self {
+                top,
+                right: horizontal,
+                bottom,
+                left: horizontal,
+            }),
+            [top, right, bottom, left] => Ok(Self {
+                top,
+                right,
+                bottom,
+                left,
+            }),
+            _ => Err(format!(
+                "Invalid margins: {}. Expected one to four lengths, like `2mm` or `1mm 2mm`",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Margins {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.top, self.right, self.bottom, self.left
+        )
+    }
+}
+
+impl TryFrom<LengthValue> for Margins {
+    type Error = String;
+
+    fn try_from(value: LengthValue) -> Result<Self, Self::Error> {
+        match value {
+            LengthValue::Number(value) => Ok(Self::uniform(Length { value, unit: None })),
+            LengthValue::Text(text) => text.parse(),
+        }
+    }
+}
+
+impl From<Margins> for LengthValue {
+    fn from(margins: Margins) -> Self {
+        LengthValue::Text(margins.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lengths() {
+        assert_eq!("2mm".parse(), Ok(Length::mm(2.0)));
+        assert_eq!(
+            "8pt".parse(),
+            Ok(Length {
+                value: 8.0,
+                unit: Some(Unit::Pt)
+            })
+        );
+        assert_eq!(" 1.5 px ".parse(), Ok(Length::px(1.5)));
+        assert_eq!(
+            "12".parse(),
+            Ok(Length {
+                value: 12.0,
+                unit: None
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_junk_lengths() {
+        for junk in ["", "mm", "2cm", "two mm", "2 mm mm", "1in"] {
+            assert!(junk.parse::<Length>().is_err(), "{:?}", junk);
+        }
+    }
+
+    #[test]
+    fn converts_to_dots() {
+        assert_eq!(Length::mm(25.4).dots(203, Unit::Px).round(), 203.0);
+        assert_eq!("72pt".parse::<Length>().unwrap().dots(300, Unit::Px), 300.0);
+        assert_eq!(Length::px(7.0).dots(203, Unit::Mm), 7.0);
+        // Bare numbers take the unit of wherever they are used
+        let bare: Length = "2.54".parse().unwrap();
+        assert_eq!(bare.dots(200, Unit::Px), 2.54);
+        assert_eq!(bare.dots(200, Unit::Mm).round(), 20.0);
+    }
+
+    #[test]
+    fn parses_margins_in_css_order() {
+        let (a, b, c, d) = (
+            Length::mm(1.0),
+            Length::mm(2.0),
+            Length::mm(3.0),
+            Length::mm(4.0),
+        );
+        assert_eq!("1mm".parse(), Ok(Margins::uniform(a)));
+        assert_eq!(
+            "1mm 2mm".parse(),
+            Ok(Margins {
+                top: a,
+                right: b,
+                bottom: a,
+                left: b
+            })
+        );
+        assert_eq!(
+            "1mm, 2mm, 3mm".parse(),
+            Ok(Margins {
+                top: a,
+                right: b,
+                bottom: c,
+                left: b
+            })
+        );
+        assert_eq!(
+            "1mm 2mm 3mm 4mm".parse(),
+            Ok(Margins {
+                top: a,
+                right: b,
+                bottom: c,
+                left: d
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_junk_margins() {
+        for junk in ["", " , ", "1mm 2mm 3mm 4mm 5mm", "1mm auto", "1cm"] {
+            assert!(junk.parse::<Margins>().is_err(), "{:?}", junk);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Label {
+            size: Length,
+            margins: Margins,
+        }
+        let label: Label = toml::from_str("size = 8\nmargins = \"1mm 2px\"").unwrap();
+        assert_eq!(
+            label,
+            Label {
+                size: Length {
+                    value: 8.0,
+                    unit: None
+                },
+                margins: Margins {
+                    top: Length::mm(1.0),
+                    right: Length::px(2.0),
+                    bottom: Length::mm(1.0),
+                    left: Length::px(2.0)
+                },
+            }
+        );
+        assert_eq!(toml::from_str(&toml::to_string(&label).unwrap()), Ok(label));
+        assert!(toml::from_str::<Label>("size = \"8furlongs\"\nmargins = 1").is_err());
+    }
+}
@@ -38,12 +38,17 @@ in {
     };
 
     resolution = lib.mkOption {
-      type = lib.types.attrsOf lib.types.str;
+      type = lib.types.attrsOf (lib.types.either lib.types.str (lib.types.attrsOf (lib.types.oneOf [lib.types.str lib.types.float lib.types.int])));
       default = {};
-      description = "Key-value list of device names for phomemo devices, alongside their actual addresses";
+      description = "Key-value list of device names for phomemo devices, alongside their actual addresses, or tables of per-device settings";
       example = {
         alice_desk = "E9:7B:61:9E:76:47";
-        bob_desk = "11:94:FC:4A:99:AC";
+        bob_desk = {
+          addr = "11:94:FC:4A:99:AC";
+          model = "q30";
+          offset_x_mm = 0.5;
+          offset_y_mm = -0.25;
+        };
       };
     };
 
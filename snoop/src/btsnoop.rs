@@ -0,0 +1,591 @@
+//! Just enough of btsnoop, HCI, L2CAP, RFCOMM and ATT to pull application
+//! payloads out of an Android `btsnoop_hci.log`.
+
+use std::{collections::HashMap, io::Read};
+
+use advmac::MacAddr6;
+use log::{debug, trace};
+use snafu::{ensure, ResultExt, Snafu};
+
+const MAGIC: &[u8; 8] = b"btsnoop\0";
+const DATALINK_HCI_UNENCAPSULATED: u32 = 1001;
+const DATALINK_HCI_UART: u32 = 1002;
+
+const H4_COMMAND: u8 = 0x01;
+const H4_ACL: u8 = 0x02;
+const H4_EVENT: u8 = 0x04;
+
+const EVENT_CONNECTION_COMPLETE: u8 = 0x03;
+const EVENT_DISCONNECTION_COMPLETE: u8 = 0x05;
+const EVENT_REMOTE_NAME: u8 = 0x07;
+const EVENT_LE_META: u8 = 0x3e;
+const LE_CONNECTION_COMPLETE: u8 = 0x01;
+const LE_ENHANCED_CONNECTION_COMPLETE: u8 = 0x0a;
+
+const CID_SIGNALING: u16 = 0x0001;
+const CID_ATT: u16 = 0x0004;
+const SIGNAL_CONNECTION_REQUEST: u8 = 0x02;
+const SIGNAL_CONNECTION_RESPONSE: u8 = 0x03;
+const PSM_RFCOMM: u16 = 0x0003;
+
+const RFCOMM_UIH: u8 = 0xef;
+const RFCOMM_PF: u8 = 0x10;
+
+const ATT_WRITE_REQUEST: u8 = 0x12;
+const ATT_WRITE_COMMAND: u8 = 0x52;
+const ATT_NOTIFICATION: u8 = 0x1b;
+const ATT_INDICATION: u8 = 0x1d;
+
+#[derive(Debug, Snafu)]
+pub enum SnoopError {
+    #[snafu(display("Failed to read btsnoop log"))]
+    CouldNotRead { source: std::io::Error },
+    #[snafu(display("Not a btsnoop file"))]
+    NotBtsnoop,
+    #[snafu(display(
+        "Unsupported btsnoop datalink type {datalink}. Only HCI (1001, 1002) logs are supported"
+    ))]
+    UnsupportedDatalink { datalink: u32 },
+}
+
+#[derive(Debug, Clone)]
+pub struct Record {
+    /// Microseconds, on an arbitrary epoch
+    pub timestamp: i64,
+    /// Travelling from the controller to the host, i.e. sent by the remote device
+    pub received: bool,
+    pub command_or_event: bool,
+    pub data: Vec<u8>,
+}
+
+pub fn read_records(mut reader: impl Read) -> Result<(u32, Vec<Record>), SnoopError> {
+    let mut header = [0u8; 16];
+    reader.read_exact(&mut header).context(CouldNotReadSnafu)?;
+    ensure!(&header[..8] == MAGIC, NotBtsnoopSnafu);
+    let datalink = u32::from_be_bytes([header[12], header[13], header[14], header[15]]);
+    ensure!(
+        matches!(datalink, DATALINK_HCI_UNENCAPSULATED | DATALINK_HCI_UART),
+        UnsupportedDatalinkSnafu { datalink }
+    );
+
+    let mut records = Vec::new();
+    let mut record_header = [0u8; 24];
+    loop {
+        match reader.read_exact(&mut record_header) {
+            Ok(()) => {}
+            // Android happily leaves a half written record at the end of the log
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context(CouldNotReadSnafu),
+        }
+        let field = |offset: usize| {
+            u32::from_be_bytes([
+                record_header[offset],
+                record_header[offset + 1],
+                record_header[offset + 2],
+                record_header[offset + 3],
+            ])
+        };
+        let included_len = field(4) as usize;
+        let flags = field(8);
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&record_header[16..24]);
+
+        let mut data = vec![0u8; included_len];
+        match reader.read_exact(&mut data) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e).context(CouldNotReadSnafu),
+        }
+        records.push(Record {
+            timestamp: i64::from_be_bytes(timestamp),
+            received: flags & 0x01 != 0,
+            command_or_event: flags & 0x02 != 0,
+            data,
+        });
+    }
+    Ok((datalink, records))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Rfcomm { dlci: u8 },
+    Gatt { handle: u16 },
+}
+
+/// Application data exchanged with a remote device
+#[derive(Debug, Clone)]
+pub struct Payload {
+    pub timestamp: i64,
+    pub device: Option<MacAddr6>,
+    pub received: bool,
+    pub channel: Channel,
+    pub bytes: Vec<u8>,
+}
+
+/// Follows connections and L2CAP channels across records, since payloads
+/// only carry handles and channel IDs.
+#[derive(Debug, Default)]
+pub struct Extractor {
+    datalink: u32,
+    /// ACL handle -> remote address
+    connections: HashMap<u16, MacAddr6>,
+    pub names: HashMap<MacAddr6, String>,
+    /// (ACL handle, received) -> partially reassembled L2CAP frame
+    fragments: HashMap<(u16, bool), Vec<u8>>,
+    /// (ACL handle, signal identifier) -> (PSM, request received, source CID)
+    pending: HashMap<(u16, u8), (u16, bool)>,
+    /// (ACL handle, received, destination CID) -> PSM
+    channels: HashMap<(u16, bool, u16), u16>,
+}
+
+fn le16(bytes: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([
+        *bytes.get(offset)?,
+        *bytes.get(offset + 1)?,
+    ]))
+}
+
+fn bdaddr(bytes: &[u8]) -> Option<MacAddr6> {
+    let mut addr: [u8; 6] = bytes.get(..6)?.try_into().ok()?;
+    // Addresses go over HCI little endian
+    addr.reverse();
+    Some(MacAddr6::new(addr))
+}
+
+impl Extractor {
+    pub fn new(datalink: u32) -> Self {
+        Self {
+            datalink,
+            ..Default::default()
+        }
+    }
+
+    pub fn feed(&mut self, record: &Record) -> Vec<Payload> {
+        let (packet_type, data) = if self.datalink == DATALINK_HCI_UART {
+            match record.data.split_first() {
+                Some((packet_type, data)) => (*packet_type, data),
+                None => return Vec::new(),
+            }
+        } else {
+            let packet_type = match (record.command_or_event, record.received) {
+                (true, true) => H4_EVENT,
+                (true, false) => H4_COMMAND,
+                (false, _) => H4_ACL,
+            };
+            (packet_type, record.data.as_slice())
+        };
+        match packet_type {
+            H4_EVENT => {
+                self.event(data);
+                Vec::new()
+            }
+            H4_ACL => self
+                .acl(record.received, data)
+                .map(|(handle, channel, bytes)| {
+                    vec![Payload {
+                        timestamp: record.timestamp,
+                        device: self.connections.get(&handle).copied(),
+                        received: record.received,
+                        channel,
+                        bytes,
+                    }]
+                })
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn event(&mut self, data: &[u8]) -> Option<()> {
+        let (code, params) = (*data.first()?, data.get(2..)?);
+        match code {
+            EVENT_CONNECTION_COMPLETE if params.first() == Some(&0) => {
+                let handle = le16(params, 1)? & 0x0fff;
+                let addr = bdaddr(params.get(3..)?)?;
+                debug!("Connection {:#05x} to {}", handle, addr);
+                self.connections.insert(handle, addr);
+            }
+            EVENT_DISCONNECTION_COMPLETE if params.first() == Some(&0) => {
+                let handle = le16(params, 1)? & 0x0fff;
+                self.connections.remove(&handle);
+                self.fragments.retain(|(h, _), _| *h != handle);
+                self.channels.retain(|(h, ..), _| *h != handle);
+            }
+            EVENT_REMOTE_NAME if params.first() == Some(&0) => {
+                let addr = bdaddr(params.get(1..)?)?;
+                let name = params.get(7..)?;
+                let name = &name[..name.iter().position(|b| *b == 0).unwrap_or(name.len())];
+                self.names
+                    .insert(addr, String::from_utf8_lossy(name).into_owned());
+            }
+            EVENT_LE_META => {
+                let sub_event = *params.first()?;
+                if matches!(
+                    sub_event,
+                    LE_CONNECTION_COMPLETE | LE_ENHANCED_CONNECTION_COMPLETE
+                ) && params.get(1) == Some(&0)
+                {
+                    let handle = le16(params, 2)? & 0x0fff;
+                    let addr = bdaddr(params.get(6..)?)?;
+                    debug!("LE connection {:#05x} to {}", handle, addr);
+                    self.connections.insert(handle, addr);
+                }
+            }
+            _ => {}
+        }
+        Some(())
+    }
+
+    fn acl(&mut self, received: bool, data: &[u8]) -> Option<(u16, Channel, Vec<u8>)> {
+        let header = le16(data, 0)?;
+        let handle = header & 0x0fff;
+        let continuation = (header >> 12) & 0x3 == 0b01;
+        let len = le16(data, 2)? as usize;
+        let fragment = data.get(4..4 + len)?;
+
+        let frame = self.fragments.entry((handle, received)).or_default();
+        if !continuation {
+            frame.clear();
+        }
+        frame.extend_from_slice(fragment);
+        let l2cap_len = le16(frame, 0)? as usize;
+        if frame.len() < 4 + l2cap_len {
+            return None;
+        }
+        let frame = self.fragments.remove(&(handle, received))?;
+        let cid = le16(&frame, 2)?;
+        let payload = &frame[4..4 + l2cap_len];
+        trace!(
+            "L2CAP {:#06x} on {:#05x}: {}",
+            cid,
+            handle,
+            hex::encode(payload)
+        );
+
+        match cid {
+            CID_SIGNALING => {
+                self.signaling(handle, received, payload);
+                None
+            }
+            CID_ATT => Self::att(payload).map(|(channel, bytes)| (handle, channel, bytes)),
+            _ => match self.channels.get(&(handle, received, cid)) {
+                Some(&PSM_RFCOMM) => {
+                    Self::rfcomm(payload).map(|(channel, bytes)| (handle, channel, bytes))
+                }
+                _ => None,
+            },
+        }
+    }
+
+    fn signaling(&mut self, handle: u16, received: bool, mut commands: &[u8]) -> Option<()> {
+        while commands.len() >= 4 {
+            let (code, identifier) = (commands[0], commands[1]);
+            let len = le16(commands, 2)? as usize;
+            let data = commands.get(4..4 + len)?;
+            match code {
+                SIGNAL_CONNECTION_REQUEST => {
+                    let psm = le16(data, 0)?;
+                    self.pending.insert((handle, identifier), (psm, received));
+                }
+                SIGNAL_CONNECTION_RESPONSE => {
+                    let destination_cid = le16(data, 0)?;
+                    let source_cid = le16(data, 2)?;
+                    let result = le16(data, 4)?;
+                    // 1 means pending. A final response with the same identifier follows
+                    if result != 1 {
+                        if let Some((psm, request_received)) =
+                            self.pending.remove(&(handle, identifier))
+                        {
+                            if result == 0 {
+                                debug!("L2CAP channel for PSM {:#06x} on {:#05x}", psm, handle);
+                                // Each side addresses its frames to the other side's channel ID
+                                self.channels
+                                    .insert((handle, request_received, destination_cid), psm);
+                                self.channels
+                                    .insert((handle, !request_received, source_cid), psm);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+            commands = &commands[4 + len..];
+        }
+        Some(())
+    }
+
+    fn rfcomm(frame: &[u8]) -> Option<(Channel, Vec<u8>)> {
+        let dlci = frame.first()? >> 2;
+        let control = *frame.get(1)?;
+        // DLCI 0 is the multiplexer's own control channel
+        if dlci == 0 || control & !RFCOMM_PF != RFCOMM_UIH {
+            return None;
+        }
+        let length_low = *frame.get(2)?;
+        let (len, mut offset) = if length_low & 0x01 != 0 {
+            ((length_low >> 1) as usize, 3)
+        } else {
+            (
+                (length_low >> 1) as usize | (*frame.get(3)? as usize) << 7,
+                4,
+            )
+        };
+        // With credit based flow control, the P/F bit announces a credit byte
+        if control & RFCOMM_PF != 0 {
+            offset += 1;
+        }
+        let bytes = frame.get(offset..offset + len)?;
+        (!bytes.is_empty()).then(|| (Channel::Rfcomm { dlci }, bytes.to_vec()))
+    }
+
+    fn att(pdu: &[u8]) -> Option<(Channel, Vec<u8>)> {
+        match *pdu.first()? {
+            ATT_WRITE_REQUEST | ATT_WRITE_COMMAND | ATT_NOTIFICATION | ATT_INDICATION => {
+                let handle = le16(pdu, 1)?;
+                Some((Channel::Gatt { handle }, pdu.get(3..)?.to_vec()))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEVICE: [u8; 6] = [0x11, 0x22, 0x33, 0x44, 0x55, 0x66];
+    const HANDLE: u16 = 0x0042;
+
+    /// A btsnoop file with `(flags, timestamp, data)` records
+    fn btsnoop(datalink: u32, records: &[(u32, i64, Vec<u8>)]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&1u32.to_be_bytes());
+        file.extend_from_slice(&datalink.to_be_bytes());
+        for (flags, timestamp, data) in records {
+            let len = (data.len() as u32).to_be_bytes();
+            file.extend_from_slice(&len);
+            file.extend_from_slice(&len);
+            file.extend_from_slice(&flags.to_be_bytes());
+            file.extend_from_slice(&0u32.to_be_bytes());
+            file.extend_from_slice(&timestamp.to_be_bytes());
+            file.extend_from_slice(data);
+        }
+        file
+    }
+
+    fn record(received: bool, data: Vec<u8>) -> Record {
+        Record {
+            timestamp: 1000,
+            received,
+            command_or_event: false,
+            data,
+        }
+    }
+
+    /// H4 ACL packet carrying `l2cap` in one piece, or a part of it with `continuation`
+    fn acl(continuation: bool, l2cap: &[u8]) -> Vec<u8> {
+        let flags = if continuation { 0x1000 } else { 0x2000 };
+        let mut packet = vec![H4_ACL];
+        packet.extend_from_slice(&(HANDLE | flags).to_le_bytes());
+        packet.extend_from_slice(&(l2cap.len() as u16).to_le_bytes());
+        packet.extend_from_slice(l2cap);
+        packet
+    }
+
+    fn l2cap(cid: u16, payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u16).to_le_bytes().to_vec();
+        frame.extend_from_slice(&cid.to_le_bytes());
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    /// RFCOMM UIH frame on `dlci`, with a dummy FCS
+    fn rfcomm(dlci: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![dlci << 2 | 0x03, RFCOMM_UIH, (payload.len() as u8) << 1 | 1];
+        frame.extend_from_slice(payload);
+        frame.push(0x00);
+        frame
+    }
+
+    /// An extractor that has seen the device connect and open an RFCOMM channel: the host sends
+    /// on CID 0x0041, the device on 0x0040
+    fn connected() -> Extractor {
+        let mut extractor = Extractor::new(DATALINK_HCI_UART);
+        let mut addr = DEVICE;
+        addr.reverse();
+        let mut event = vec![H4_EVENT, EVENT_CONNECTION_COMPLETE, 11, 0x00];
+        event.extend_from_slice(&HANDLE.to_le_bytes());
+        event.extend_from_slice(&addr);
+        event.extend_from_slice(&[0x01, 0x00]);
+        let request = [
+            SIGNAL_CONNECTION_REQUEST,
+            0x01,
+            0x04,
+            0x00,
+            0x03,
+            0x00,
+            0x40,
+            0x00,
+        ];
+        let response = [
+            SIGNAL_CONNECTION_RESPONSE,
+            0x01,
+            0x08,
+            0x00,
+            0x41,
+            0x00,
+            0x40,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+            0x00,
+        ];
+        assert!(extractor.feed(&record(true, event)).is_empty());
+        assert!(extractor
+            .feed(&record(false, acl(false, &l2cap(CID_SIGNALING, &request))))
+            .is_empty());
+        assert!(extractor
+            .feed(&record(true, acl(false, &l2cap(CID_SIGNALING, &response))))
+            .is_empty());
+        extractor
+    }
+
+    #[test]
+    fn reads_records() {
+        let file = btsnoop(
+            DATALINK_HCI_UART,
+            &[
+                (0b00, 1, vec![0x02, 0xaa]),
+                (0b11, 2, vec![0x04, 0xbb, 0xcc]),
+            ],
+        );
+        let (datalink, records) = read_records(file.as_slice()).unwrap();
+        assert_eq!(datalink, DATALINK_HCI_UART);
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            (records[0].timestamp, records[0].received, &records[0].data),
+            (1, false, &vec![0x02, 0xaa])
+        );
+        assert!(records[1].received && records[1].command_or_event);
+        assert_eq!(records[1].data, vec![0x04, 0xbb, 0xcc]);
+    }
+
+    #[test]
+    fn drops_a_truncated_last_record() {
+        let mut file = btsnoop(
+            DATALINK_HCI_UNENCAPSULATED,
+            &[(0, 1, vec![0xaa, 0xbb]), (0, 2, vec![0xcc, 0xdd])],
+        );
+        file.pop();
+        let (_, records) = read_records(file.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+
+        // Cut off inside the record header
+        file.truncate(16 + 24 + 2 + 10);
+        let (_, records) = read_records(file.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(
+            read_records(&b"not a btsnoop log"[..]),
+            Err(SnoopError::NotBtsnoop)
+        ));
+        assert!(matches!(
+            read_records(&b"btsnoop"[..]),
+            Err(SnoopError::CouldNotRead { .. })
+        ));
+        assert!(matches!(
+            read_records(btsnoop(2001, &[]).as_slice()),
+            Err(SnoopError::UnsupportedDatalink { datalink: 2001 })
+        ));
+    }
+
+    #[test]
+    fn extracts_rfcomm_payloads() {
+        let mut extractor = connected();
+        let payloads = extractor.feed(&record(
+            false,
+            acl(false, &l2cap(0x0041, &rfcomm(2, &[0x1f, 0x11, 0x08]))),
+        ));
+        assert_eq!(payloads.len(), 1);
+        let payload = &payloads[0];
+        assert_eq!(payload.device, Some(MacAddr6::new(DEVICE)));
+        assert_eq!(payload.channel, Channel::Rfcomm { dlci: 2 });
+        assert_eq!(payload.bytes, vec![0x1f, 0x11, 0x08]);
+        assert!(!payload.received);
+
+        let payloads = extractor.feed(&record(
+            true,
+            acl(false, &l2cap(0x0040, &rfcomm(2, &[0x1a, 0x04, 0x50]))),
+        ));
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].received);
+        assert_eq!(payloads[0].bytes, vec![0x1a, 0x04, 0x50]);
+    }
+
+    #[test]
+    fn reassembles_fragmented_frames() {
+        let mut extractor = connected();
+        let frame = l2cap(0x0041, &rfcomm(2, &[0x1b, 0x40, 0x1f, 0x11, 0x12]));
+        let (first, rest) = frame.split_at(6);
+        assert!(extractor.feed(&record(false, acl(false, first))).is_empty());
+        let payloads = extractor.feed(&record(false, acl(true, rest)));
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].bytes, vec![0x1b, 0x40, 0x1f, 0x11, 0x12]);
+    }
+
+    #[test]
+    fn ignores_truncated_and_unknown_packets() {
+        let mut extractor = connected();
+        // ACL length beyond the end of the packet
+        let mut packet = acl(false, &l2cap(0x0041, &rfcomm(2, &[0x1b, 0x40])));
+        packet.truncate(packet.len() - 3);
+        assert!(extractor.feed(&record(false, packet)).is_empty());
+        // A channel that was never opened
+        assert!(extractor
+            .feed(&record(
+                false,
+                acl(false, &l2cap(0x0077, &rfcomm(2, &[0x01])))
+            ))
+            .is_empty());
+        // RFCOMM control channel, and a frame that isn't UIH
+        assert!(extractor
+            .feed(&record(
+                false,
+                acl(false, &l2cap(0x0041, &rfcomm(0, &[0x01])))
+            ))
+            .is_empty());
+        let mut sabm = rfcomm(2, &[0x01]);
+        sabm[1] = 0x3f;
+        assert!(extractor
+            .feed(&record(false, acl(false, &l2cap(0x0041, &sabm))))
+            .is_empty());
+        // ATT read request
+        assert!(extractor
+            .feed(&record(
+                false,
+                acl(false, &l2cap(CID_ATT, &[0x0a, 0x10, 0x00]))
+            ))
+            .is_empty());
+        // Unknown H4 packet type, and an empty record
+        assert!(extractor.feed(&record(false, vec![0x09, 0x00])).is_empty());
+        assert!(extractor.feed(&record(false, vec![])).is_empty());
+    }
+
+    #[test]
+    fn extracts_gatt_writes() {
+        let mut extractor = connected();
+        let payloads = extractor.feed(&record(
+            false,
+            acl(
+                false,
+                &l2cap(CID_ATT, &[ATT_WRITE_COMMAND, 0x10, 0x00, 0x1b, 0x40]),
+            ),
+        ));
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].channel, Channel::Gatt { handle: 0x0010 });
+        assert_eq!(payloads[0].bytes, vec![0x1b, 0x40]);
+    }
+}
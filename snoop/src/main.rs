@@ -0,0 +1,153 @@
+//! Pulls the traffic between a phone app and a Phomemo printer out of an
+//! Android `btsnoop_hci.log`, and annotates it with what we know about the protocol.
+
+mod btsnoop;
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::BufReader,
+    path::PathBuf,
+    time::Duration,
+};
+
+use advmac::MacAddr6;
+use btsnoop::{Channel, Extractor, SnoopError};
+use clap::Parser;
+use d30::{
+    capture::{CaptureWriter, Direction},
+    protocol::{CommandKind, Decoder},
+    D30Error,
+};
+use log::warn;
+use snafu::{ResultExt, Snafu};
+
+#[derive(Debug, Parser)]
+#[command(name = "d30-snoop")]
+#[command(
+    version,
+    about = "Decode Phomemo printer traffic from an Android btsnoop log."
+)]
+struct Arguments {
+    /// `btsnoop_hci.log`, as pulled from a phone with HCI snoop logging enabled
+    log: PathBuf,
+    /// Only show traffic with this device
+    #[arg(short, long)]
+    device: Option<MacAddr6>,
+    /// Also write the traffic in the capture format, for `d30-cli replay`
+    #[arg(short, long)]
+    #[arg(requires = "device")]
+    capture: Option<PathBuf>,
+    /// Only print raw payloads, without decoding them
+    #[arg(long)]
+    raw: bool,
+}
+
+#[derive(Debug, Snafu)]
+enum SnoopCLIError {
+    #[snafu(display("Failed to open {}", path.display()))]
+    CouldNotOpen {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[snafu(display("Failed to parse btsnoop log"))]
+    InvalidLog { source: SnoopError },
+    #[snafu(display("Failed to write capture"))]
+    CouldNotWriteCapture { source: D30Error },
+}
+
+fn main() -> Result<(), snafu::Report<SnoopCLIError>> {
+    env_logger::init_from_env(
+        env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "warn"),
+    );
+    snafu::Report::capture_into_result(|| run(Arguments::parse()))
+}
+
+fn run(args: Arguments) -> Result<(), SnoopCLIError> {
+    let file = File::open(&args.log).context(CouldNotOpenSnafu { path: &args.log })?;
+    let (datalink, records) =
+        btsnoop::read_records(BufReader::new(file)).context(InvalidLogSnafu)?;
+
+    let mut capture = match (&args.capture, args.device) {
+        (Some(path), Some(device)) => {
+            Some(CaptureWriter::create(path, device).context(CouldNotWriteCaptureSnafu)?)
+        }
+        _ => None,
+    };
+
+    let mut extractor = Extractor::new(datalink);
+    let mut decoders: HashMap<(Option<MacAddr6>, bool, Channel), Decoder> = HashMap::new();
+    let mut unknown_commands: BTreeMap<u8, usize> = BTreeMap::new();
+    let mut start = None;
+    let mut payloads = 0;
+
+    for record in &records {
+        for payload in extractor.feed(record) {
+            if args.device.is_some() && payload.device != args.device {
+                continue;
+            }
+            payloads += 1;
+            let start = *start.get_or_insert(payload.timestamp);
+            let offset = Duration::from_micros((payload.timestamp - start).max(0) as u64);
+
+            let device = payload
+                .device
+                .map(|device| device.to_string())
+                .unwrap_or_else(|| "unknown device".to_string());
+            let channel = match payload.channel {
+                Channel::Rfcomm { dlci } => format!("rfcomm/{}", dlci),
+                Channel::Gatt { handle } => format!("gatt/{:#06x}", handle),
+            };
+            println!(
+                "{:10.6} {} {} {} ({} bytes)",
+                offset.as_secs_f64(),
+                if payload.received { "<" } else { ">" },
+                device,
+                channel,
+                payload.bytes.len()
+            );
+
+            if let Some(capture) = &mut capture {
+                let direction = if payload.received {
+                    Direction::Received
+                } else {
+                    Direction::Sent
+                };
+                capture
+                    .record_at(offset, direction, &payload.bytes)
+                    .context(CouldNotWriteCaptureSnafu)?;
+            }
+
+            if args.raw {
+                println!("    {}", hex::encode(&payload.bytes));
+                continue;
+            }
+            let decoder = decoders
+                .entry((payload.device, payload.received, payload.channel))
+                .or_default();
+            for decoded in decoder.feed(&payload.bytes) {
+                if let CommandKind::Unknown1f11 { code } = decoded.kind {
+                    *unknown_commands.entry(code).or_default() += 1;
+                }
+                println!("    {}", decoded);
+            }
+        }
+    }
+
+    if payloads == 0 {
+        warn!("No RFCOMM or GATT payloads found. Was the printer connected while the log was recorded?");
+    }
+    if !extractor.names.is_empty() {
+        println!("\nDevices:");
+        for (device, name) in &extractor.names {
+            println!("    {} {}", device, name);
+        }
+    }
+    if !unknown_commands.is_empty() {
+        println!("\nUnknown commands:");
+        for (code, count) in unknown_commands {
+            println!("    1f11{:02x} seen {} times", code, count);
+        }
+    }
+    Ok(())
+}